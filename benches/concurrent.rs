@@ -0,0 +1,61 @@
+//! Throughput of one agent shared by concurrent ssh sessions. Each session does what
+//! ssh does on connect: lists identities and signs with one of the keys. Sessions
+//! using different keys shouldn't wait for each other, so throughput grows with threads
+
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use ssh_keys::{Agent, KeyPair, PrivateKey, RequestInfo};
+use ssh_keys::agent::AgentRequest;
+use ssh_keys::config::Config;
+
+const SESSIONS: usize = 64;
+
+fn agent() -> Arc<Agent> {
+    let key_pair = |key, name: &str| KeyPair::new(key, name.to_string()).unwrap();
+    let mut config = Config::default();
+    config.keys.push(key_pair(PrivateKey::generate_rsa(4096).unwrap(), "rsa"));
+    config.keys.push(key_pair(PrivateKey::generate_ed25519().unwrap(), "ed25519"));
+    Arc::new(Agent::new(config, Box::new(|_: &KeyPair, _: &RequestInfo, _: &Config| true)))
+}
+
+fn session(agent: &Agent, key_idx: usize) {
+    let info = RequestInfo { channel: "Bench", requester: None };
+    agent.handle_request(&AgentRequest::RequestIdentities.encode().unwrap(), &info);
+
+    let key = agent.config().keys[key_idx].public().clone();
+    let sign = AgentRequest::SignRequest { key, data: vec![0; 128], flags: 0 };
+    agent.handle_request(&sign.encode().unwrap(), &info);
+}
+
+fn concurrent_sessions(c: &mut Criterion) {
+    let agent = agent();
+
+    let mut group = c.benchmark_group("concurrent_sessions");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SESSIONS as u64));
+    for threads in [1, 4, 16].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(threads), threads, |b, &threads| {
+            b.iter(|| {
+                let handles: Vec<_> = (0..threads)
+                    .map(|thread_idx| {
+                        let agent = agent.clone();
+                        thread::spawn(move || {
+                            for session_idx in (thread_idx..SESSIONS).step_by(threads) {
+                                session(&agent, session_idx % 2);
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_sessions);
+criterion_main!(benches);
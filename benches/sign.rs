@@ -0,0 +1,74 @@
+//! Signing with key prepared once per `KeyPair` against preparing it on every request.
+//! Allocations per signature are counted by a wrapping global allocator and printed
+//! before the timings
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use ssh_keys::{KeyPair, PrivateKey};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const MSG: [u8; 128] = [0; 128];
+const RSA_SHA2_256: u32 = 0x2;
+
+fn keys() -> Vec<(&'static str, KeyPair)> {
+    let key_pair = |key, name: &str| KeyPair::new(key, name.to_string()).unwrap();
+    vec![
+        ("rsa-2048", key_pair(PrivateKey::generate_rsa(2048).unwrap(), "rsa")),
+        ("ecdsa-256", key_pair(PrivateKey::generate_ecdsa(256).unwrap(), "ecdsa")),
+        ("ed25519", key_pair(PrivateKey::generate_ed25519().unwrap(), "ed25519")),
+    ]
+}
+
+/// Rust allocations only, allocations made by openssl itself aren't counted
+fn allocations<F: FnMut()>(mut f: F) -> usize {
+    const ROUNDS: usize = 100;
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..ROUNDS {
+        f();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) / ROUNDS
+}
+
+fn sign(c: &mut Criterion) {
+    let keys = keys();
+
+    for (name, key_pair) in &keys {
+        println!("{}: {} allocations per signature with prepared key, {} without", name,
+                 allocations(|| { key_pair.sign(&MSG, RSA_SHA2_256).unwrap(); }),
+                 allocations(|| { key_pair.private().sign(&MSG, RSA_SHA2_256).unwrap(); }));
+    }
+
+    let mut group = c.benchmark_group("sign");
+    for (name, key_pair) in &keys {
+        group.bench_with_input(BenchmarkId::new("prepared", name), key_pair, |b, key_pair| {
+            b.iter(|| key_pair.sign(&MSG, RSA_SHA2_256).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("unprepared", name), key_pair, |b, key_pair| {
+            b.iter(|| key_pair.private().sign(&MSG, RSA_SHA2_256).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sign);
+criterion_main!(benches);
@@ -1,5 +1,9 @@
 fn main() {
-    if cfg!(windows) {
-        embed_resource::compile("./src/platform/win/ssh-agent.rc")
+    // embed-resource is only a build dependency on windows
+    #[cfg(windows)]
+    {
+        if std::env::var_os("CARGO_FEATURE_PLATFORM").is_some() {
+            embed_resource::compile("./src/platform/win/ssh-agent.rc")
+        }
     }
 }
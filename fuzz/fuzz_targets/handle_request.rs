@@ -0,0 +1,23 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use ssh_keys::{Agent, KeyPair, RequestInfo};
+use ssh_keys::agent::wire::ReadExt;
+use ssh_keys::config::Config;
+
+// Input is a sequence of length-prefixed messages handled by one agent, so that
+// e.g. sign requests can reach keys added by previous messages
+fuzz_target!(|data: &[u8]| {
+    // Config without path is never saved
+    let agent = Agent::new(Config::default(),
+                               Box::new(|_: &KeyPair, _: &RequestInfo, _: &Config| true));
+    let info = RequestInfo { channel: "Fuzz", requester: None };
+
+    let mut input = Cursor::new(data);
+    while let Ok(msg) = input.read_string() {
+        let resp = agent.handle_request(&msg, &info);
+        assert!(!resp.is_empty());
+    }
+});
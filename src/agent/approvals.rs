@@ -0,0 +1,147 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::agent::RequestInfo;
+use crate::key::KeyPair;
+
+/// Key use allowed without confirmation for the same requester until it expires
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Approval {
+    pub id: u64,
+    pub key_name: String,
+    pub fingerprint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+    /// Seconds since unix epoch
+    pub expires: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+fn requester(info: &RequestInfo) -> Option<String> {
+    info.requester.as_ref().map(|requester| requester.description_long.clone())
+}
+
+#[derive(Default)]
+pub(crate) struct Approvals {
+    next_id: u64,
+    approvals: Vec<Approval>,
+}
+
+impl Approvals {
+    fn prune(&mut self) {
+        let now = now();
+        self.approvals.retain(|approval| approval.expires > now);
+    }
+
+    pub fn list(&mut self) -> Vec<Approval> {
+        self.prune();
+        self.approvals.clone()
+    }
+
+    /// `None` if requester is unknown, e.g. on unix, as approval would then allow any process
+    pub fn add(&mut self, key_pair: &KeyPair, info: &RequestInfo, duration: Duration)
+               -> Option<Approval> {
+        let requester = requester(info)?;
+        self.next_id += 1;
+        let approval = Approval {
+            id: self.next_id,
+            key_name: key_pair.name().to_string(),
+            fingerprint: key_pair.public().fingerprint(),
+            requester: Some(requester),
+            expires: now() + duration.as_secs(),
+        };
+        self.approvals.push(approval.clone());
+        Some(approval)
+    }
+
+    pub fn revoke(&mut self, id: u64) -> bool {
+        let len = self.approvals.len();
+        self.approvals.retain(|approval| approval.id != id);
+        self.approvals.len() != len
+    }
+
+    /// Id of approval allowing requester to use key
+    pub fn find(&mut self, key_pair: &KeyPair, info: &RequestInfo) -> Option<u64> {
+        self.prune();
+        let fingerprint = key_pair.public().fingerprint();
+        let requester = requester(info)?;
+        self.approvals.iter()
+            .find(|approval| {
+                approval.fingerprint == fingerprint
+                    && approval.requester.as_ref() == Some(&requester)
+            })
+            .map(|approval| approval.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::agent::RequesterInfo;
+    use crate::key::PrivateKey;
+
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn key_pair() -> KeyPair {
+        KeyPair::new(PrivateKey::generate_ed25519().unwrap(), "test".to_string()).unwrap()
+    }
+
+    fn info(requester: Option<&str>) -> RequestInfo {
+        RequestInfo {
+            channel: "Test",
+            requester: requester.map(|description| RequesterInfo {
+                description_short: description.to_string(),
+                description_long: description.to_string(),
+                pid: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn same_requester_and_key() {
+        let (key, other_key) = (key_pair(), key_pair());
+        let mut approvals = Approvals::default();
+        let approval = approvals.add(&key, &info(Some("ssh")), HOUR).unwrap();
+        assert_eq!(approvals.find(&key, &info(Some("ssh"))), Some(approval.id));
+        assert_eq!(approvals.find(&key, &info(Some("git"))), None);
+        assert_eq!(approvals.find(&key, &info(None)), None);
+        assert_eq!(approvals.find(&other_key, &info(Some("ssh"))), None);
+    }
+
+    #[test]
+    fn unknown_requester() {
+        let key = key_pair();
+        let mut approvals = Approvals::default();
+        assert!(approvals.add(&key, &info(None), HOUR).is_none());
+        assert_eq!(approvals.find(&key, &info(None)), None);
+        assert!(approvals.list().is_empty());
+    }
+
+    #[test]
+    fn expiry() {
+        let key = key_pair();
+        let mut approvals = Approvals::default();
+        approvals.add(&key, &info(Some("ssh")), Duration::from_secs(0)).unwrap();
+        assert_eq!(approvals.find(&key, &info(Some("ssh"))), None);
+        assert!(approvals.list().is_empty());
+    }
+
+    #[test]
+    fn revoke() {
+        let key = key_pair();
+        let mut approvals = Approvals::default();
+        let first = approvals.add(&key, &info(Some("ssh")), HOUR).unwrap();
+        let second = approvals.add(&key, &info(Some("git")), HOUR).unwrap();
+        assert_ne!(first.id, second.id);
+        assert!(approvals.revoke(first.id));
+        assert!(!approvals.revoke(first.id));
+        assert_eq!(approvals.find(&key, &info(Some("ssh"))), None);
+        assert_eq!(approvals.find(&key, &info(Some("git"))), Some(second.id));
+        assert_eq!(approvals.list().len(), 1);
+    }
+}
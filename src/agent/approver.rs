@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::agent::{Agent, RequestInfo};
+use crate::agent::wire::ReadExt;
+use crate::key::KeyPair;
+
+/// Pending request is denied if approver doesn't decide in time
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+
+/// Sign request waiting for decision of attached approver
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingRequest {
+    pub id: u64,
+    pub key_name: String,
+    pub fingerprint: String,
+    pub channel: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+    /// Remote user, if signed data is ssh user authentication request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ApproverMessage {
+    Pending(PendingRequest),
+    /// Request wasn't decided in time and was denied
+    Expired(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub approve: bool,
+    /// Approve further requests of the same requester for this long
+    pub remember: Option<Duration>,
+}
+
+/// Remote user of `SSH_MSG_USERAUTH_REQUEST` signed during public key authentication
+pub(crate) fn userauth_user(data: &[u8]) -> Option<String> {
+    let mut r = Cursor::new(data);
+    let _session_id = r.read_string().ok()?;
+    let mut msg_type = [0];
+    std::io::Read::read_exact(&mut r, &mut msg_type).ok()?;
+    if msg_type[0] != SSH_MSG_USERAUTH_REQUEST {
+        return None;
+    }
+    r.read_string_utf8().ok()
+}
+
+#[derive(Default)]
+pub(crate) struct ApproverState {
+    next_id: AtomicU64,
+    approver: Mutex<Option<(u64, UnboundedSender<ApproverMessage>)>>,
+    /// Request id -> approver session and channel of waiting request
+    pending: Mutex<HashMap<u64, (u64, mpsc::Sender<Decision>)>>,
+}
+
+/// Attached approver, detached when dropped. Requests it didn't decide go to
+/// confirmation provider then
+pub struct ApproverSession {
+    agent: Arc<Agent>,
+    id: u64,
+    receiver: UnboundedReceiver<ApproverMessage>,
+}
+
+impl ApproverSession {
+    pub async fn recv(&mut self) -> Option<ApproverMessage> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for ApproverSession {
+    fn drop(&mut self) {
+        let state = &self.agent.approver;
+        let mut approver = state.approver.lock().unwrap();
+        if approver.as_ref().is_some_and(|(id, _)| *id == self.id) {
+            *approver = None;
+        }
+        // Waiting requests see disconnected channel
+        state.pending.lock().unwrap().retain(|_, (session, _)| *session != self.id);
+    }
+}
+
+impl Agent {
+    /// Routes confirmations to the caller, `None` if another approver is attached
+    pub fn attach_approver(self: &Arc<Self>) -> Option<ApproverSession> {
+        let state = &self.approver;
+        let mut approver = state.approver.lock().unwrap();
+        if approver.is_some() {
+            return None;
+        }
+        let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = unbounded_channel();
+        *approver = Some((id, sender));
+        Some(ApproverSession { agent: self.clone(), id, receiver })
+    }
+
+    pub fn has_approver(&self) -> bool {
+        self.approver.approver.lock().unwrap().is_some()
+    }
+
+    /// Returns false if request isn't pending anymore
+    pub fn decide(&self, id: u64, decision: Decision) -> bool {
+        match self.approver.pending.lock().unwrap().remove(&id) {
+            Some((_, sender)) => sender.send(decision).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Asks attached approver, `None` if there is none or it detached before deciding
+    pub(crate) fn ask_approver(&self, key_pair: &KeyPair, info: &RequestInfo, data: &[u8])
+                               -> Option<bool> {
+        let state = &self.approver;
+        let (session, sender) = state.approver.lock().unwrap().clone()?;
+        let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+        let (decision_sender, decisions) = mpsc::channel();
+        state.pending.lock().unwrap().insert(id, (session, decision_sender));
+
+        let request = PendingRequest {
+            id,
+            key_name: key_pair.name().to_string(),
+            fingerprint: key_pair.public().fingerprint(),
+            channel: info.channel.to_string(),
+            requester: info.requester.as_ref()
+                .map(|requester| requester.description_long.clone()),
+            user: userauth_user(data),
+        };
+        if sender.send(ApproverMessage::Pending(request)).is_err() {
+            state.pending.lock().unwrap().remove(&id);
+            return None;
+        }
+
+        match decisions.recv_timeout(APPROVAL_TIMEOUT) {
+            Ok(decision) => {
+                if let (true, Some(duration)) = (decision.approve, decision.remember) {
+                    if self.remember_approval(key_pair, info, duration).is_none() {
+                        warn!("requester is unknown, approval isn't remembered");
+                    }
+                }
+                Some(decision.approve)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                state.pending.lock().unwrap().remove(&id);
+                let _ = sender.send(ApproverMessage::Expired(id));
+                Some(false)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::agent::wire::WriteExt;
+
+    use super::*;
+
+    fn userauth_request(msg_type: u8, user: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_string([7; 32]).unwrap();
+        data.push(msg_type);
+        data.write_string(user).unwrap();
+        data.write_string("ssh-connection").unwrap();
+        data
+    }
+
+    #[test]
+    fn userauth_users() {
+        assert_eq!(userauth_user(&userauth_request(SSH_MSG_USERAUTH_REQUEST, "git")),
+                   Some("git".to_string()));
+        assert_eq!(userauth_user(&userauth_request(SSH_MSG_USERAUTH_REQUEST + 1, "git")), None);
+        assert_eq!(userauth_user(b"arbitrary data"), None);
+        assert_eq!(userauth_user(&[]), None);
+    }
+}
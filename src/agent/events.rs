@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+/// Changes of agent state, delivered to subscribers such as management clients
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AgentEvent {
+    KeyAdded { name: String, fingerprint: String, ephemeral: bool },
+    KeyRemoved { fingerprint: String },
+    KeyRenamed { fingerprint: String, name: String },
+    ConfigReloaded,
+    Locked,
+    Unlocked,
+    SignRequest {
+        name: String,
+        fingerprint: String,
+        channel: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        requester: Option<String>,
+        approved: bool,
+    },
+    ApprovalRevoked { id: u64 },
+}
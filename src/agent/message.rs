@@ -0,0 +1,473 @@
+use std::io::{Cursor, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use wrapperrs::{Error, Result, ResultExt};
+
+use crate::agent::wire::{ensure_consumed, ReadExt, WriteExt};
+use crate::key::{KeyPair, PublicKey};
+
+pub const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+pub const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+pub const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+pub const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+pub const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+pub const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY: u8 = 20;
+pub const SSH_AGENTC_REMOVE_SMARTCARD_KEY: u8 = 21;
+pub const SSH_AGENTC_LOCK: u8 = 22;
+pub const SSH_AGENTC_UNLOCK: u8 = 23;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED: u8 = 26;
+pub const SSH_AGENTC_EXTENSION: u8 = 27;
+
+pub const SSH_AGENT_FAILURE: u8 = 5;
+pub const SSH_AGENT_SUCCESS: u8 = 6;
+pub const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+pub const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+pub const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+
+pub const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+pub const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+pub const SSH_AGENT_CONSTRAIN_EXTENSION: u8 = 255;
+
+pub struct Identity {
+    pub key: PublicKey,
+    pub comment: String,
+}
+
+pub enum KeyConstraint {
+    /// Key should be removed after this number of seconds
+    Lifetime(u32),
+    Confirm,
+    /// Extension data isn't length-prefixed, so it takes the rest of the message
+    Extension { name: String, data: Vec<u8> },
+}
+
+pub enum AgentRequest {
+    RequestIdentities,
+    SignRequest { key: PublicKey, data: Vec<u8>, flags: u32 },
+    AddIdentity { key_pair: KeyPair },
+    AddIdConstrained { key_pair: KeyPair, constraints: Vec<KeyConstraint> },
+    RemoveIdentity { key: PublicKey },
+    RemoveAllIdentities,
+    AddSmartcardKey { id: String, pin: String },
+    AddSmartcardKeyConstrained { id: String, pin: String, constraints: Vec<KeyConstraint> },
+    RemoveSmartcardKey { id: String, pin: String },
+    Lock { passphrase: String },
+    Unlock { passphrase: String },
+    Extension { name: String, contents: Vec<u8> },
+    Unknown { msg_type: u8, contents: Vec<u8> },
+}
+
+pub enum AgentResponse {
+    Failure,
+    Success,
+    /// Success carrying extension-specific data
+    ExtensionSuccess(Vec<u8>),
+    ExtensionFailure,
+    IdentitiesAnswer(Vec<Identity>),
+    SignResponse(Vec<u8>),
+    Unknown { msg_type: u8, contents: Vec<u8> },
+}
+
+fn read_rest<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let mut rest = Vec::new();
+    r.read_to_end(&mut rest)?;
+    Ok(rest)
+}
+
+impl KeyConstraint {
+    fn read_list<R: Read>(r: &mut R) -> Result<Vec<KeyConstraint>> {
+        let mut constraints = Vec::new();
+        loop {
+            let constraint_type = match r.read_u8() {
+                Ok(constraint_type) => constraint_type,
+                Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            };
+            constraints.push(match constraint_type {
+                SSH_AGENT_CONSTRAIN_LIFETIME => KeyConstraint::Lifetime(
+                    r.read_u32::<BigEndian>().wrap_err("read lifetime")?),
+                SSH_AGENT_CONSTRAIN_CONFIRM => KeyConstraint::Confirm,
+                SSH_AGENT_CONSTRAIN_EXTENSION => {
+                    let name = r.read_string_utf8().wrap_err("read extension name")?;
+                    let data = read_rest(r)?;
+                    KeyConstraint::Extension { name, data }
+                }
+                _ => return Err(Error::new(
+                    &format!("unknown constraint: {}", constraint_type)).into()),
+            });
+        }
+        Ok(constraints)
+    }
+
+    fn write_list<W: Write>(constraints: &[KeyConstraint], w: &mut W) -> Result<()> {
+        for constraint in constraints {
+            match constraint {
+                KeyConstraint::Lifetime(seconds) => {
+                    w.write_u8(SSH_AGENT_CONSTRAIN_LIFETIME)?;
+                    w.write_u32::<BigEndian>(*seconds)?;
+                }
+                KeyConstraint::Confirm => w.write_u8(SSH_AGENT_CONSTRAIN_CONFIRM)?,
+                KeyConstraint::Extension { name, data } => {
+                    w.write_u8(SSH_AGENT_CONSTRAIN_EXTENSION)?;
+                    w.write_string(name)?;
+                    w.write_all(data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AgentRequest {
+    pub fn decode(buf: &[u8]) -> Result<AgentRequest> {
+        use AgentRequest::*;
+
+        let mut r = Cursor::new(buf);
+        let msg_type = r.read_u8().wrap_err("read msg_type")?;
+        Ok((|| -> Result<AgentRequest> {
+            let request = match msg_type {
+                SSH_AGENTC_REQUEST_IDENTITIES => RequestIdentities,
+                SSH_AGENTC_SIGN_REQUEST => SignRequest {
+                    key: PublicKey::decode(&r.read_string()?).wrap_err("read public key")?,
+                    data: r.read_string().wrap_err("read data")?,
+                    flags: r.read_u32::<BigEndian>().wrap_err("read flags")?,
+                },
+                SSH_AGENTC_ADD_IDENTITY => AddIdentity {
+                    key_pair: KeyPair::from_wire(&mut r).wrap_err("read key_pair")?,
+                },
+                SSH_AGENTC_ADD_ID_CONSTRAINED => AddIdConstrained {
+                    key_pair: KeyPair::from_wire(&mut r).wrap_err("read key_pair")?,
+                    constraints: KeyConstraint::read_list(&mut r).wrap_err("read constraints")?,
+                },
+                SSH_AGENTC_REMOVE_IDENTITY => RemoveIdentity {
+                    key: PublicKey::decode(&r.read_string()?).wrap_err("read public key")?,
+                },
+                SSH_AGENTC_REMOVE_ALL_IDENTITIES => RemoveAllIdentities,
+                SSH_AGENTC_ADD_SMARTCARD_KEY => AddSmartcardKey {
+                    id: r.read_string_utf8()?,
+                    pin: r.read_string_utf8()?,
+                },
+                SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED => AddSmartcardKeyConstrained {
+                    id: r.read_string_utf8()?,
+                    pin: r.read_string_utf8()?,
+                    constraints: KeyConstraint::read_list(&mut r).wrap_err("read constraints")?,
+                },
+                SSH_AGENTC_REMOVE_SMARTCARD_KEY => RemoveSmartcardKey {
+                    id: r.read_string_utf8()?,
+                    pin: r.read_string_utf8()?,
+                },
+                SSH_AGENTC_LOCK => Lock { passphrase: r.read_string_utf8()? },
+                SSH_AGENTC_UNLOCK => Unlock { passphrase: r.read_string_utf8()? },
+                SSH_AGENTC_EXTENSION => Extension {
+                    name: r.read_string_utf8().wrap_err("read extension name")?,
+                    contents: read_rest(&mut r)?,
+                },
+                _ => Unknown { msg_type, contents: read_rest(&mut r)? },
+            };
+            ensure_consumed(&r)?;
+            Ok(request)
+        })().wrap_err(&format!("msg_type={}", msg_type))?)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        use AgentRequest::*;
+
+        let mut buf = Vec::new();
+        match self {
+            RequestIdentities => buf.write_u8(SSH_AGENTC_REQUEST_IDENTITIES)?,
+            SignRequest { key, data, flags } => {
+                buf.write_u8(SSH_AGENTC_SIGN_REQUEST)?;
+                buf.write_string(key.encode())?;
+                buf.write_string(data)?;
+                buf.write_u32::<BigEndian>(*flags)?;
+            }
+            AddIdentity { key_pair } => {
+                buf.write_u8(SSH_AGENTC_ADD_IDENTITY)?;
+                key_pair.to_wire(&mut buf)?;
+            }
+            AddIdConstrained { key_pair, constraints } => {
+                buf.write_u8(SSH_AGENTC_ADD_ID_CONSTRAINED)?;
+                key_pair.to_wire(&mut buf)?;
+                KeyConstraint::write_list(constraints, &mut buf)?;
+            }
+            RemoveIdentity { key } => {
+                buf.write_u8(SSH_AGENTC_REMOVE_IDENTITY)?;
+                buf.write_string(key.encode())?;
+            }
+            RemoveAllIdentities => buf.write_u8(SSH_AGENTC_REMOVE_ALL_IDENTITIES)?,
+            AddSmartcardKey { id, pin } => {
+                buf.write_u8(SSH_AGENTC_ADD_SMARTCARD_KEY)?;
+                buf.write_string(id)?;
+                buf.write_string(pin)?;
+            }
+            AddSmartcardKeyConstrained { id, pin, constraints } => {
+                buf.write_u8(SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED)?;
+                buf.write_string(id)?;
+                buf.write_string(pin)?;
+                KeyConstraint::write_list(constraints, &mut buf)?;
+            }
+            RemoveSmartcardKey { id, pin } => {
+                buf.write_u8(SSH_AGENTC_REMOVE_SMARTCARD_KEY)?;
+                buf.write_string(id)?;
+                buf.write_string(pin)?;
+            }
+            Lock { passphrase } => {
+                buf.write_u8(SSH_AGENTC_LOCK)?;
+                buf.write_string(passphrase)?;
+            }
+            Unlock { passphrase } => {
+                buf.write_u8(SSH_AGENTC_UNLOCK)?;
+                buf.write_string(passphrase)?;
+            }
+            Extension { name, contents } => {
+                buf.write_u8(SSH_AGENTC_EXTENSION)?;
+                buf.write_string(name)?;
+                buf.write_all(contents)?;
+            }
+            Unknown { msg_type, contents } => {
+                buf.write_u8(*msg_type)?;
+                buf.write_all(contents)?;
+            }
+        }
+        Ok(buf)
+    }
+}
+
+impl AgentResponse {
+    pub fn decode(buf: &[u8]) -> Result<AgentResponse> {
+        use AgentResponse::*;
+
+        let mut r = Cursor::new(buf);
+        let msg_type = r.read_u8().wrap_err("read msg_type")?;
+        Ok((|| -> Result<AgentResponse> {
+            let response = match msg_type {
+                SSH_AGENT_FAILURE => Failure,
+                SSH_AGENT_SUCCESS => {
+                    let contents = read_rest(&mut r)?;
+                    if contents.is_empty() { Success } else { ExtensionSuccess(contents) }
+                }
+                SSH_AGENT_EXTENSION_FAILURE => ExtensionFailure,
+                SSH_AGENT_IDENTITIES_ANSWER => {
+                    let count = r.read_u32::<BigEndian>().wrap_err("read count")?;
+                    IdentitiesAnswer((0..count)
+                        .map(|_| -> Result<Identity> {
+                            Ok(Identity {
+                                key: PublicKey::decode(&r.read_string()?)
+                                    .wrap_err("read public key")?,
+                                comment: r.read_string_utf8().wrap_err("read comment")?,
+                            })
+                        })
+                        .collect::<Result<_>>()?)
+                }
+                SSH_AGENT_SIGN_RESPONSE => {
+                    SignResponse(r.read_string().wrap_err("read signature")?)
+                }
+                _ => Unknown { msg_type, contents: read_rest(&mut r)? },
+            };
+            ensure_consumed(&r)?;
+            Ok(response)
+        })().wrap_err(&format!("msg_type={}", msg_type))?)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        use AgentResponse::*;
+
+        let mut buf = Vec::new();
+        (|| -> std::io::Result<()> {
+            match self {
+                Failure => buf.write_u8(SSH_AGENT_FAILURE)?,
+                Success => buf.write_u8(SSH_AGENT_SUCCESS)?,
+                ExtensionSuccess(contents) => {
+                    buf.write_u8(SSH_AGENT_SUCCESS)?;
+                    buf.write_all(contents)?;
+                }
+                ExtensionFailure => buf.write_u8(SSH_AGENT_EXTENSION_FAILURE)?,
+                IdentitiesAnswer(identities) => {
+                    buf.write_u8(SSH_AGENT_IDENTITIES_ANSWER)?;
+                    buf.write_u32::<BigEndian>(identities.len() as u32)?;
+                    for identity in identities {
+                        buf.write_string(identity.key.encode())?;
+                        buf.write_string(&identity.comment)?;
+                    }
+                }
+                SignResponse(signature) => {
+                    buf.write_u8(SSH_AGENT_SIGN_RESPONSE)?;
+                    buf.write_string(signature)?;
+                }
+                Unknown { msg_type, contents } => {
+                    buf.write_u8(*msg_type)?;
+                    buf.write_all(contents)?;
+                }
+            }
+            Ok(())
+        })().expect("write to vec");
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::PrivateKey;
+
+    fn key_pairs() -> Vec<KeyPair> {
+        vec![
+            KeyPair::new(PrivateKey::generate_rsa(2048).unwrap(), "rsa".to_string()).unwrap(),
+            KeyPair::new(PrivateKey::generate_ecdsa(256).unwrap(), "ecdsa".to_string()).unwrap(),
+            KeyPair::new(PrivateKey::generate_ed25519().unwrap(), "ed25519".to_string()).unwrap(),
+        ]
+    }
+
+    fn constraints() -> Vec<KeyConstraint> {
+        vec![
+            KeyConstraint::Lifetime(600),
+            KeyConstraint::Confirm,
+            KeyConstraint::Extension { name: "ext@test".to_string(), data: vec![1, 2, 3] },
+        ]
+    }
+
+    fn requests() -> Vec<AgentRequest> {
+        use AgentRequest::*;
+
+        let mut requests = vec![
+            RequestIdentities,
+            RemoveAllIdentities,
+            AddSmartcardKey { id: "id".to_string(), pin: "1234".to_string() },
+            AddSmartcardKeyConstrained {
+                id: "id".to_string(),
+                pin: "1234".to_string(),
+                constraints: constraints(),
+            },
+            RemoveSmartcardKey { id: "id".to_string(), pin: "".to_string() },
+            Lock { passphrase: "secret".to_string() },
+            Unlock { passphrase: "secret".to_string() },
+            Extension { name: "query".to_string(), contents: vec![] },
+            Extension { name: "rename@ssh-keys".to_string(), contents: vec![0, 0, 0, 1, 7] },
+            Unknown { msg_type: 200, contents: vec![1, 2, 3] },
+        ];
+        for key_pair in key_pairs() {
+            let key = key_pair.public().clone();
+            requests.push(SignRequest { key: key.clone(), data: vec![0; 64], flags: 2 });
+            requests.push(RemoveIdentity { key });
+            requests.push(AddIdentity { key_pair: key_pair.clone() });
+            requests.push(AddIdConstrained { key_pair: key_pair.clone(), constraints: vec![] });
+            requests.push(AddIdConstrained { key_pair, constraints: constraints() });
+        }
+        requests
+    }
+
+    fn responses() -> Vec<AgentResponse> {
+        use AgentResponse::*;
+
+        let identities = key_pairs().iter()
+            .map(|key_pair| Identity {
+                key: key_pair.public().clone(),
+                comment: key_pair.name().to_string(),
+            })
+            .collect();
+        vec![
+            Failure,
+            Success,
+            ExtensionSuccess(vec![1, 2, 3]),
+            ExtensionFailure,
+            IdentitiesAnswer(vec![]),
+            IdentitiesAnswer(identities),
+            SignResponse(vec![7; 64]),
+            Unknown { msg_type: 200, contents: vec![1, 2, 3] },
+        ]
+    }
+
+    fn same_variant<T>(a: &T, b: &T) -> bool {
+        std::mem::discriminant(a) == std::mem::discriminant(b)
+    }
+
+    #[test]
+    fn request_round_trip() {
+        for request in requests() {
+            let buf = request.encode().unwrap();
+            let decoded = AgentRequest::decode(&buf).unwrap();
+            assert!(same_variant(&request, &decoded));
+            assert_eq!(decoded.encode().unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        for response in responses() {
+            let buf = response.encode();
+            let decoded = AgentResponse::decode(&buf).unwrap();
+            assert!(same_variant(&response, &decoded));
+            assert_eq!(decoded.encode(), buf);
+        }
+    }
+
+    #[test]
+    fn truncated_request() {
+        // Extensions, unknown messages and extension constraints take the rest of the message,
+        // so only messages of known length are truncated at every position
+        let requests = requests().into_iter().filter(|request| match request {
+            AgentRequest::Extension { .. } | AgentRequest::Unknown { .. } => false,
+            AgentRequest::AddIdConstrained { constraints, .. }
+            | AgentRequest::AddSmartcardKeyConstrained { constraints, .. } => {
+                constraints.is_empty()
+            }
+            _ => true,
+        });
+        for request in requests {
+            let buf = request.encode().unwrap();
+            for len in 0..buf.len() {
+                assert!(AgentRequest::decode(&buf[..len]).is_err(), "msg_type={} len={}", buf[0],
+                        len);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_response() {
+        let responses = responses().into_iter().filter(|response| {
+            matches!(response, AgentResponse::IdentitiesAnswer(_) | AgentResponse::SignResponse(_))
+        });
+        for response in responses {
+            let buf = response.encode();
+            for len in 0..buf.len() {
+                assert!(AgentResponse::decode(&buf[..len]).is_err(), "len={}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        // Constraints are read until the end of the message
+        let requests = requests().into_iter().filter(|request| !matches!(request,
+            AgentRequest::Extension { .. } | AgentRequest::Unknown { .. }
+            | AgentRequest::AddIdConstrained { .. }
+            | AgentRequest::AddSmartcardKeyConstrained { .. }));
+        for request in requests {
+            let mut buf = request.encode().unwrap();
+            buf.push(0);
+            assert!(AgentRequest::decode(&buf).is_err(), "msg_type={}", buf[0]);
+        }
+
+        for response in responses() {
+            let mut buf = response.encode();
+            buf.push(0);
+            let decoded = AgentResponse::decode(&buf);
+            match response {
+                // Rest of the message is their contents
+                AgentResponse::Success | AgentResponse::ExtensionSuccess(_)
+                | AgentResponse::Unknown { .. } => assert!(decoded.is_ok()),
+                _ => assert!(decoded.is_err(), "msg_type={}", buf[0]),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_constraint() {
+        let key_pair = &key_pairs()[2];
+        let mut buf = AgentRequest::AddIdentity { key_pair: key_pair.clone() }.encode().unwrap();
+        buf[0] = SSH_AGENTC_ADD_ID_CONSTRAINED;
+        buf.push(100);
+        assert!(AgentRequest::decode(&buf).is_err());
+    }
+}
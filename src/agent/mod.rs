@@ -1,54 +1,76 @@
 use std::io::Cursor;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use log::error;
+use log::{error, info, warn};
+use openssl::sha::sha256;
+use tokio::sync::broadcast;
 use wrapperrs::{Error, ErrorExt, Result, ResultExt};
 
-use wire::{ReadExt, WriteExt};
+pub use approvals::Approval;
+use approvals::Approvals;
+pub use approver::{ApproverMessage, ApproverSession, Decision, PendingRequest};
+use approver::ApproverState;
+pub use events::AgentEvent;
+pub use message::*;
+use wire::{ensure_consumed, ReadExt, WriteExt};
 
+use crate::audit::{AuditDecision, AuditLog, AuditOp, AuditRecord};
 use crate::config::Config;
 use crate::key::{KeyPair, PublicKey};
-use crate::platform::ask_confirmation;
-
-const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
-const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
-const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
-#[allow(dead_code)]
-const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
-#[allow(dead_code)]
-const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
-#[allow(dead_code)]
-const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
-#[allow(dead_code)]
-const SSH_AGENTC_ADD_SMARTCARD_KEY: u8 = 20;
-#[allow(dead_code)]
-const SSH_AGENTC_REMOVE_SMARTCARD_KEY: u8 = 21;
-#[allow(dead_code)]
-const SSH_AGENTC_LOCK: u8 = 22;
-#[allow(dead_code)]
-const SSH_AGENTC_UNLOCK: u8 = 23;
-#[allow(dead_code)]
-const SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED: u8 = 26;
-#[allow(dead_code)]
-const SSH_AGENTC_EXTENSION: u8 = 27;
-
-const SSH_AGENT_FAILURE: u8 = 5;
-const SSH_AGENT_SUCCESS: u8 = 6;
-#[allow(dead_code)]
-const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
-const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
-const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+use crate::stats::{KeyStats, UsageStats};
 
+/// Lists supported extensions, clients use it to tell ssh-keys from other agents
+pub const EXTENSION_QUERY: &str = "query";
+pub const EXTENSION_RENAME: &str = "rename@ssh-keys";
+/// Key constraint, key is kept in memory only
+pub const CONSTRAINT_EPHEMERAL: &str = "ephemeral@ssh-keys";
+
+mod approvals;
+mod approver;
+mod events;
+pub mod message;
 pub mod wire;
 
+/// Events not received by slow subscribers in time are dropped
+const EVENTS_CAPACITY: usize = 64;
+
+/// Decides whether key can be used for request, e.g. by asking the user
+pub trait ConfirmationProvider: Send + Sync {
+    fn confirm(&self, key_pair: &KeyPair, info: &RequestInfo, config: &Config) -> bool;
+}
+
+impl<F> ConfirmationProvider for F
+    where F: Fn(&KeyPair, &RequestInfo, &Config) -> bool + Send + Sync
+{
+    fn confirm(&self, key_pair: &KeyPair, info: &RequestInfo, config: &Config) -> bool {
+        self(key_pair, info, config)
+    }
+}
+
+/// Agent shared between connections. Keys are an immutable snapshot, changes are made
+/// to a copy which then replaces it, so requests never wait for signing, confirmation
+/// dialogs or config writes of other requests
 pub struct Agent {
-    config: Config,
+    config: RwLock<Arc<Config>>,
+    /// Serializes config changes, so that concurrent changes aren't lost
+    update: Mutex<()>,
+    confirmation: Box<dyn ConfirmationProvider>,
+    /// Hash of lock passphrase while agent is locked
+    lock_hash: Mutex<Option<[u8; 32]>>,
+    approvals: Mutex<Approvals>,
+    /// Attached approver takes precedence over `confirmation`
+    approver: ApproverState,
+    events: broadcast::Sender<AgentEvent>,
+    audit: Option<AuditLog>,
+    stats: Mutex<UsageStats>,
 }
 
 #[derive(Debug)]
 pub struct RequesterInfo {
     pub description_short: String,
     pub description_long: String,
+    pub pid: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -57,86 +79,435 @@ pub struct RequestInfo {
     pub requester: Option<RequesterInfo>,
 }
 
+fn key_index(config: &Config, public: &PublicKey) -> Option<usize> {
+    config.keys.iter().position(|key_pair| key_pair.public() == public)
+}
+
 impl Agent {
-    pub fn new(config: Config) -> Agent {
-        Agent { config }
+    pub fn new(config: Config, confirmation: Box<dyn ConfirmationProvider>) -> Agent {
+        Agent {
+            config: RwLock::new(Arc::new(config)),
+            update: Mutex::new(()),
+            confirmation,
+            lock_hash: Mutex::new(None),
+            approvals: Mutex::new(Approvals::default()),
+            approver: ApproverState::default(),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            audit: None,
+            stats: Mutex::new(UsageStats::default()),
+        }
+    }
+
+    /// Tracks key usage in `stats`. Added and removed keys are saved right away, uses only
+    /// by [`Agent::flush_stats`], so that signing doesn't wait for a file write
+    pub fn with_usage_stats(mut self, stats: UsageStats) -> Agent {
+        self.stats = Mutex::new(stats);
+        self
+    }
+
+    pub fn key_stats(&self, public: &PublicKey) -> Option<KeyStats> {
+        self.stats.lock().unwrap().get(&public.fingerprint()).cloned()
+    }
+
+    fn update_stats<F: FnOnce(&mut UsageStats)>(&self, f: F) {
+        f(&mut self.stats.lock().unwrap());
+    }
+
+    /// Saves usage stats if they changed since last save
+    pub fn flush_stats(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        if stats.is_dirty() {
+            if let Err(err) = stats.save() {
+                error!("{}", err.wrap("save usage stats"));
+            }
+        }
+    }
+
+    /// Records key usage and changes made by clients in `audit`
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Agent {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub(crate) fn audit(&self, record: AuditRecord) {
+        if let Some(audit) = &self.audit {
+            if let Err(err) = audit.record(&record) {
+                error!("{}", err.wrap("write audit log"));
+            }
+        }
+    }
+
+    /// Receives events emitted after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<AgentEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: AgentEvent) {
+        // Fails only when there are no subscribers
+        let _ = self.events.send(event);
+    }
+
+    /// Current snapshot of config, it isn't affected by later changes
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Applies `f` to a copy of config, saves it and replaces the current snapshot.
+    /// Config file isn't rewritten if only ephemeral keys changed
+    pub fn update_config<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&mut Config) -> Result<T>
+    {
+        let _update = self.update.lock().unwrap();
+        let current = self.config();
+        let mut config = Config::clone(&current);
+        let result = f(&mut config)?;
+        if !config.same_on_disk(&current)? {
+            config.save().wrap_err("save config")?;
+        }
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(result)
+    }
+
+    pub fn reload_config(&self) -> Result<()> {
+        let _update = self.update.lock().unwrap();
+        let mut config = Config::clone(&self.config());
+        config.reload()?;
+        *self.config.write().unwrap() = Arc::new(config);
+        self.emit(AgentEvent::ConfigReloaded);
+        Ok(())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock_hash.lock().unwrap().is_some()
+    }
+
+    /// While locked, no keys are listed or used. Returns false if agent is locked already
+    pub fn lock(&self, passphrase: &str) -> bool {
+        let mut lock_hash = self.lock_hash.lock().unwrap();
+        if lock_hash.is_some() {
+            return false;
+        }
+        *lock_hash = Some(sha256(passphrase.as_bytes()));
+        self.emit(AgentEvent::Locked);
+        true
+    }
+
+    /// Returns false if agent isn't locked or passphrase doesn't match
+    pub fn unlock(&self, passphrase: &str) -> bool {
+        let mut lock_hash = self.lock_hash.lock().unwrap();
+        if *lock_hash != Some(sha256(passphrase.as_bytes())) {
+            return false;
+        }
+        *lock_hash = None;
+        self.emit(AgentEvent::Unlocked);
+        true
+    }
+
+    /// Unexpired approvals
+    pub fn approvals(&self) -> Vec<Approval> {
+        self.approvals.lock().unwrap().list()
     }
 
-    pub fn config(&self) -> &Config {
-        &self.config
+    /// Allows requester to use key without confirmation for `duration`, `None` if requester
+    /// is unknown
+    pub fn remember_approval(&self, key_pair: &KeyPair, info: &RequestInfo, duration: Duration)
+                             -> Option<Approval> {
+        self.approvals.lock().unwrap().add(key_pair, info, duration)
     }
 
-    pub fn config_mut(&mut self) -> &mut Config {
-        &mut self.config
+    pub fn revoke_approval(&self, id: u64) -> bool {
+        let revoked = self.approvals.lock().unwrap().revoke(id);
+        if revoked {
+            self.emit(AgentEvent::ApprovalRevoked { id });
+        }
+        revoked
+    }
+
+    pub fn add_key(&self, key_pair: KeyPair) -> Result<()> {
+        let fingerprint = key_pair.public().fingerprint();
+        let event = AgentEvent::KeyAdded {
+            name: key_pair.name().to_string(),
+            fingerprint: fingerprint.clone(),
+            ephemeral: key_pair.is_ephemeral(),
+        };
+        let added = self.update_config(|config| {
+            if key_index(config, key_pair.public()).is_some() {
+                return Ok(false);
+            }
+            config.keys.push(key_pair);
+            Ok(true)
+        })?;
+        if added {
+            self.update_stats(|stats| stats.created(&fingerprint));
+            self.flush_stats();
+            self.emit(event);
+        }
+        Ok(())
     }
 
-    fn get_key(&self, public: &PublicKey) -> Option<(&KeyPair, usize)> {
-        self.config
-            .keys
-            .iter()
-            .enumerate()
-            .find(|(_, key_pair)| key_pair.public() == public)
-            .map(|(idx, key_pair)| (key_pair, idx))
+    pub fn remove_key(&self, public: &PublicKey) -> Result<bool> {
+        let removed = self.update_config(|config| {
+            Ok(match key_index(config, public) {
+                Some(idx) => {
+                    config.keys.remove(idx);
+                    true
+                }
+                None => false,
+            })
+        })?;
+        if removed {
+            let fingerprint = public.fingerprint();
+            self.update_stats(|stats| {
+                stats.remove(&fingerprint);
+            });
+            self.flush_stats();
+            self.emit(AgentEvent::KeyRemoved { fingerprint });
+        }
+        Ok(removed)
     }
 
-    pub fn add_key(&mut self, key_pair: KeyPair) -> Result<()> {
-        if let None = self.get_key(key_pair.public()) {
-            self.config.keys.push(key_pair);
-            self.config.save().wrap_err("save config")?;
+    pub fn rename_key(&self, public: &PublicKey, name: String) -> Result<()> {
+        let event = AgentEvent::KeyRenamed {
+            fingerprint: public.fingerprint(),
+            name: name.clone(),
         };
+        self.update_config(|config| {
+            let idx = key_index(config, public).ok_or(Error::new("key not found"))?;
+            if config.keys.iter().enumerate().any(|(i, k)| i != idx && k.name() == name) {
+                return Err(Error::new("key with this name already exists").into());
+            }
+            config.keys[idx].set_name(name);
+            Ok(())
+        })?;
+        self.emit(event);
         Ok(())
     }
 
-    fn handle_request_internal(&mut self, buf: &[u8], info: &RequestInfo) -> Result<Vec<u8>> {
-        let mut req = Cursor::new(buf);
-        let mut resp = Vec::new();
-        let msg_type = req.read_u8().wrap_err("read msg_type")?;
-        (|| -> Result<()> {
-            match msg_type {
-                SSH_AGENTC_REQUEST_IDENTITIES => {
-                    resp.write_u8(SSH_AGENT_IDENTITIES_ANSWER)?;
-                    resp.write_u32::<BigEndian>(self.config.keys.len() as u32)?;
-                    for key_pair in &self.config.keys {
-                        resp.write_string(key_pair.public().encode())?;
-                        resp.write_string(&key_pair.name())?;
+    /// Changes to keys are confirmed like signing, so that any client of the socket,
+    /// e.g. a forwarded agent, can't remove or rename keys unnoticed
+    fn confirm_change(&self, public: &PublicKey, info: &RequestInfo) -> bool {
+        let config = self.config();
+        match key_index(&config, public) {
+            Some(idx) => self.confirmation.confirm(&config.keys[idx], info, &config),
+            None => false,
+        }
+    }
+
+    fn handle_extension(&self, name: &str, contents: &[u8], info: &RequestInfo)
+                        -> Result<AgentResponse> {
+        let mut req = Cursor::new(contents);
+        Ok(match name {
+            EXTENSION_QUERY => {
+                ensure_consumed(&req)?;
+                let mut names = Vec::new();
+                for name in &[EXTENSION_QUERY, EXTENSION_RENAME] {
+                    names.write_string(name)?;
+                }
+                AgentResponse::ExtensionSuccess(names)
+            }
+            EXTENSION_RENAME => {
+                let pub_key = PublicKey::decode(&req.read_string()?)
+                    .wrap_err("read public key")?;
+                let name = req.read_string_utf8().wrap_err("read name")?;
+                ensure_consumed(&req)?;
+                info!("rename key fingerprint={} name={} channel={}", pub_key.fingerprint(), name,
+                      info.channel);
+                if !self.confirm_change(&pub_key, info) {
+                    info!("rename key denied fingerprint={}", pub_key.fingerprint());
+                    return Ok(AgentResponse::Failure);
+                }
+                self.rename_key(&pub_key, name)?;
+                AgentResponse::Success
+            }
+            _ => AgentResponse::Failure,
+        })
+    }
+
+    fn handle_message(&self, request: AgentRequest, info: &RequestInfo) -> Result<AgentResponse> {
+        use AgentRequest::*;
+
+        let lock_hash = *self.lock_hash.lock().unwrap();
+        if let Some(lock_hash) = lock_hash {
+            return Ok(match request {
+                RequestIdentities => {
+                    self.audit(AuditRecord::new(AuditOp::List, info, AuditDecision::Denied)
+                        .rule("locked"));
+                    AgentResponse::IdentitiesAnswer(Vec::new())
+                }
+                Unlock { passphrase } if sha256(passphrase.as_bytes()) == lock_hash => {
+                    if !self.unlock(&passphrase) {
+                        return Ok(AgentResponse::Failure);
                     }
+                    info!("agent unlocked channel={}", info.channel);
+                    self.audit(AuditRecord::new(AuditOp::Unlock, info, AuditDecision::Allowed)
+                        .rule("passphrase"));
+                    AgentResponse::Success
+                }
+                Unlock { .. } => {
+                    self.audit(AuditRecord::new(AuditOp::Unlock, info, AuditDecision::Denied)
+                        .rule("passphrase"));
+                    AgentResponse::Failure
                 }
-                SSH_AGENTC_ADD_IDENTITY => {
-                    let key_pair = KeyPair::from_wire(&mut req).wrap_err("read key_pair")?;
-                    self.add_key(key_pair).wrap_err("add key")?;
-                    resp.write_u8(SSH_AGENT_SUCCESS)?;
+                SignRequest { key, .. } => {
+                    self.audit(AuditRecord::new(AuditOp::Sign, info, AuditDecision::Denied)
+                        .key(None, &key).rule("locked"));
+                    AgentResponse::Failure
                 }
-                SSH_AGENTC_SIGN_REQUEST => {
-                    let pub_key = PublicKey::decode(&req.read_string()?)
-                        .wrap_err("read public key")?;
-                    let msg = req.read_string().wrap_err("read msg")?;
-                    let flags = req.read_u32::<BigEndian>().wrap_err("read flags")?;
-                    let (key_pair, _) = self.get_key(&pub_key)
-                        .ok_or(Error::new("key not found"))?;
-
-                    if ask_confirmation(key_pair, info, self.config()) {
-                        resp.write_u8(SSH_AGENT_SIGN_RESPONSE)?;
-                        resp.write_string(key_pair.private().sign(&msg, flags).wrap_err("sign")?)?;
-                    } else {
-                        resp.write_u8(SSH_AGENT_FAILURE)?;
+                _ => AgentResponse::Failure,
+            });
+        }
+
+        Ok(match request {
+            RequestIdentities => {
+                self.audit(AuditRecord::new(AuditOp::List, info, AuditDecision::Allowed));
+                AgentResponse::IdentitiesAnswer(self.config()
+                    .keys
+                    .iter()
+                    .map(|key_pair| Identity {
+                        key: key_pair.public().clone(),
+                        comment: key_pair.name().to_string(),
+                    })
+                    .collect())
+            }
+            AddIdentity { key_pair } => {
+                info!("add key name={} fingerprint={} channel={}", key_pair.name(),
+                      key_pair.public().fingerprint(), info.channel);
+                let record = AuditRecord::new(AuditOp::Add, info, AuditDecision::Allowed)
+                    .key(Some(key_pair.name()), key_pair.public());
+                self.add_key(key_pair).wrap_err("add key")?;
+                self.audit(record);
+                AgentResponse::Success
+            }
+            AddIdConstrained { mut key_pair, constraints } => {
+                // Every use of a key is confirmed on windows, other platforms have no
+                // confirmation dialog so it's refused rather than silently ignored
+                for constraint in &constraints {
+                    match constraint {
+                        KeyConstraint::Confirm if cfg!(windows) => {}
+                        KeyConstraint::Extension { name, .. } if name == CONSTRAINT_EPHEMERAL => {
+                            key_pair.set_ephemeral(true);
+                        }
+                        _ => {
+                            warn!("unsupported key constraint name={} channel={}",
+                                  key_pair.name(), info.channel);
+                            return Ok(AgentResponse::Failure);
+                        }
                     }
                 }
-                _ => {
-                    resp.write_u8(SSH_AGENT_FAILURE)?;
+                info!("add key name={} fingerprint={} ephemeral={} channel={}", key_pair.name(),
+                      key_pair.public().fingerprint(), key_pair.is_ephemeral(), info.channel);
+                let record = AuditRecord::new(AuditOp::Add, info, AuditDecision::Allowed)
+                    .key(Some(key_pair.name()), key_pair.public());
+                self.add_key(key_pair).wrap_err("add key")?;
+                self.audit(record);
+                AgentResponse::Success
+            }
+            SignRequest { key, data, flags } => {
+                let config = self.config();
+                let key_pair = key_index(&config, &key)
+                    .map(|idx| &config.keys[idx])
+                    .ok_or(Error::new("key not found"))?;
+
+                info!("sign request name={} fingerprint={} channel={}", key_pair.name(),
+                      key_pair.public().fingerprint(), info.channel);
+                let approval = self.approvals.lock().unwrap().find(key_pair, info);
+                let (approved, rule) = match approval {
+                    Some(id) => (true, format!("approval {}", id)),
+                    None => match self.ask_approver(key_pair, info, &data) {
+                        Some(approved) => (approved, "approver".to_string()),
+                        None => (self.confirmation.confirm(key_pair, info, &config),
+                                 "confirmation".to_string()),
+                    },
+                };
+                let decision = if approved {
+                    AuditDecision::Allowed
+                } else {
+                    AuditDecision::Denied
+                };
+                self.audit(AuditRecord::new(AuditOp::Sign, info, decision)
+                    .key(Some(key_pair.name()), key_pair.public())
+                    .destination(approver::userauth_user(&data))
+                    .rule(&rule));
+                self.emit(AgentEvent::SignRequest {
+                    name: key_pair.name().to_string(),
+                    fingerprint: key_pair.public().fingerprint(),
+                    channel: info.channel.to_string(),
+                    requester: info.requester.as_ref()
+                        .map(|requester| requester.description_short.clone()),
+                    approved,
+                });
+                if approved {
+                    info!("sign request approved fingerprint={} rule={}",
+                          key_pair.public().fingerprint(), rule);
+                    let signature = key_pair.sign(&data, flags).wrap_err("sign")?;
+                    self.update_stats(|stats| stats.used(
+                        &key_pair.public().fingerprint(),
+                        info.requester.as_ref()
+                            .map(|requester| requester.description_short.clone()),
+                        approver::userauth_user(&data),
+                    ));
+                    AgentResponse::SignResponse(signature)
+                } else {
+                    info!("sign request denied fingerprint={} rule={}",
+                          key_pair.public().fingerprint(), rule);
+                    AgentResponse::Failure
+                }
+            }
+            RemoveIdentity { key } => {
+                info!("remove key fingerprint={} channel={}", key.fingerprint(), info.channel);
+                if !self.confirm_change(&key, info) {
+                    info!("remove key denied fingerprint={}", key.fingerprint());
+                    self.audit(AuditRecord::new(AuditOp::Remove, info, AuditDecision::Denied)
+                        .key(None, &key).rule("confirmation"));
+                    return Ok(AgentResponse::Failure);
                 }
-            };
-            Ok(())
-        })().wrap_err(&format!("msg_type={}", msg_type))?;
-        Ok(resp)
+                if self.remove_key(&key).wrap_err("remove key")? {
+                    self.audit(AuditRecord::new(AuditOp::Remove, info, AuditDecision::Allowed)
+                        .key(None, &key).rule("confirmation"));
+                    AgentResponse::Success
+                } else {
+                    AgentResponse::Failure
+                }
+            }
+            Lock { passphrase } => {
+                if !self.lock(&passphrase) {
+                    return Ok(AgentResponse::Failure);
+                }
+                info!("agent locked channel={}", info.channel);
+                self.audit(AuditRecord::new(AuditOp::Lock, info, AuditDecision::Allowed));
+                AgentResponse::Success
+            }
+            Extension { name, contents } => self.handle_extension(&name, &contents, info)
+                .wrap_err(&format!("extension {}", name))?,
+            // Keys are stored persistently, so removing all of them at once isn't allowed
+            RemoveAllIdentities => {
+                self.audit(AuditRecord::new(AuditOp::Remove, info, AuditDecision::Denied)
+                    .rule("remove all"));
+                AgentResponse::Failure
+            }
+            AddSmartcardKey { .. } | AddSmartcardKeyConstrained { .. }
+            | RemoveSmartcardKey { .. } | Unlock { .. } | Unknown { .. } => AgentResponse::Failure,
+        })
     }
 
-    pub fn handle_request(&mut self, buf: &[u8], info: &RequestInfo) -> Vec<u8> {
-        match self.handle_request_internal(buf, info) {
-            Ok(resp) => resp,
+    pub fn handle_request(&self, buf: &[u8], info: &RequestInfo) -> Vec<u8> {
+        let response = (|| -> Result<AgentResponse> {
+            let request = AgentRequest::decode(buf).wrap_err("decode request")?;
+            self.handle_message(request, info)
+        })();
+        match response {
+            Ok(response) => response.encode(),
             Err(err) => {
                 error!("{}", err.wrap("error handling request"));
-                vec![SSH_AGENT_FAILURE]
+                AgentResponse::Failure.encode()
             }
         }
     }
 }
+
+/// Uses recorded since last flush aren't lost when agent or command exits
+impl Drop for Agent {
+    fn drop(&mut self) {
+        self.flush_stats();
+    }
+}
@@ -1,14 +1,25 @@
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use openssl::bn::{BigNum, BigNumRef};
-use wrapperrs::{Result, ResultExt};
+use wrapperrs::{Error, Result, ResultExt};
+
+/// Agents must refuse messages longer than 256 KiB
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024;
 
 pub trait ReadExt: Read {
+    /// Reads length-prefixed string. Nothing is allocated beyond data actually read,
+    /// so reading from a frame is bounded by the frame
     fn read_string(&mut self) -> Result<Vec<u8>> {
-        let len = self.read_u32::<BigEndian>().wrap_err("read len")?;
-        let mut content = vec![0; len as usize];
-        self.read_exact(&mut content).wrap_err("read content")?;
+        let len = self.read_u32::<BigEndian>().wrap_err("read len")? as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(Error::new(&format!("string too long: {}", len)).into());
+        }
+        let mut content = Vec::new();
+        self.take(len as u64).read_to_end(&mut content).wrap_err("read content")?;
+        if content.len() != len {
+            return Err(Error::new("string is truncated").into());
+        }
         Ok(content)
     }
 
@@ -17,7 +28,7 @@ pub trait ReadExt: Read {
     }
 
     fn read_mpint(&mut self) -> Result<BigNum> {
-        Ok(BigNum::from_slice(&self.read_string()?).unwrap())
+        Ok(BigNum::from_slice(&self.read_string()?).wrap_err("decode mpint")?)
     }
 }
 
@@ -31,8 +42,9 @@ pub trait WriteExt: Write {
     }
 
     fn write_mpint(&mut self, bn: &BigNumRef) -> std::io::Result<()> {
+        // Zero is encoded as empty string
         let mut bytes = bn.to_vec();
-        if bytes[0] & 0x80 > 0 {
+        if bytes.first().is_some_and(|b| b & 0x80 > 0) {
             bytes.insert(0, 0);
         }
         self.write_string(bytes)
@@ -40,3 +52,13 @@ pub trait WriteExt: Write {
 }
 
 impl<T: Write> WriteExt for T {}
+
+/// Fails if anything is left unread in `cur`
+pub fn ensure_consumed<T: AsRef<[u8]>>(cur: &Cursor<T>) -> Result<()> {
+    let len = cur.get_ref().as_ref().len() as u64;
+    if cur.position() < len {
+        let left = len - cur.position();
+        return Err(Error::new(&format!("{} bytes of trailing data", left)).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::io::{stdin, stdout, Read, Write};
+use std::time::Duration;
+
+use clap::ArgMatches;
+use wrapperrs::{Error, ErrorExt, Result, ResultExt};
+
+use ssh_keys::agent::PendingRequest;
+use ssh_keys::control::{ControlClient, ResponseBody};
+
+/// Reads single keypress without waiting for enter
+#[cfg(unix)]
+fn read_key() -> Result<u8> {
+    use ssh_keys::utils::Finally;
+
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    let is_tty = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } == 0;
+    let _restore = Finally::new(|| {
+        if is_tty {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original) };
+        }
+    });
+    if is_tty {
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error().wrap("set terminal mode").into());
+        }
+    }
+    let mut key = [0];
+    stdin().read_exact(&mut key).wrap_err("read key")?;
+    Ok(key[0])
+}
+
+/// Console reads whole lines, first character is the key
+#[cfg(windows)]
+fn read_key() -> Result<u8> {
+    let mut line = String::new();
+    stdin().read_line(&mut line).wrap_err("read key")?;
+    Ok(line.bytes().next().unwrap_or(b'\n'))
+}
+
+fn describe(request: &PendingRequest) -> String {
+    let mut description = format!("key {} ({})", request.key_name, request.fingerprint);
+    if let Some(user) = &request.user {
+        description += &format!(" as user {}", user);
+    }
+    description += &format!("\n  requested by {} via {}",
+                            request.requester.as_deref().unwrap_or("unknown process"),
+                            request.channel);
+    description
+}
+
+/// Asks user until a valid key is pressed, returns approval and remember duration.
+/// Approval can't be remembered for unknown requester, as it would apply to any process
+fn ask(request: &PendingRequest, remember: Duration) -> Result<(bool, Option<Duration>)> {
+    let remember = Some(remember).filter(|_| request.requester.is_some());
+    println!("Sign request {}: {}", request.id, describe(request));
+    print!("  [y] approve  [n] deny");
+    if let Some(remember) = remember {
+        print!("  [r] approve and remember for {} minutes", remember.as_secs() / 60);
+    }
+    print!(": ");
+    stdout().flush()?;
+    loop {
+        let decision = match read_key()? {
+            b'y' | b'Y' => (true, None),
+            b'n' | b'N' => (false, None),
+            b'r' | b'R' if remember.is_some() => (true, remember),
+            _ => continue,
+        };
+        println!("{}", if decision.0 { "approved" } else { "denied" });
+        return Ok(decision);
+    }
+}
+
+/// Attaches as the agent's approver, sign requests are decided here instead of by
+/// the confirmation dialog as long as this runs
+pub fn approve(opts: &ArgMatches) -> Result<()> {
+    let watch = opts.is_present("watch");
+    let minutes: u64 = opts.value_of("remember").unwrap().parse()
+        .map_err(|_| Error::new("invalid number of minutes"))?;
+    let remember = Duration::from_secs(minutes * 60);
+
+    let mut client = ControlClient::connect().wrap_err("connect to agent control socket")?;
+    client.attach_approver()?;
+    if watch {
+        println!("Waiting for sign requests, press Ctrl+C to stop");
+    }
+
+    loop {
+        match client.next_message()? {
+            ResponseBody::Pending { request } => {
+                let (approve, remember) = ask(&request, remember)?;
+                client.decide(request.id, approve, remember)?;
+                if !watch {
+                    return Ok(());
+                }
+            }
+            ResponseBody::Expired { id } => {
+                println!("Sign request {} expired and was denied", id);
+            }
+            ResponseBody::Error { message } => eprintln!("{}", message),
+            _ => return Err(Error::new("unexpected response").into()),
+        }
+    }
+}
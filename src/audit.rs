@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use wrapperrs::{Error, ErrorExt, Result, ResultExt};
+
+use crate::agent::RequestInfo;
+use crate::key::PublicKey;
+use crate::logging::{RotatingFile, rotated_path};
+
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOp {
+    List,
+    Sign,
+    Add,
+    Remove,
+    Lock,
+    Unlock,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditDecision {
+    Allowed,
+    Denied,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditRecord {
+    /// Seconds since unix epoch
+    pub timestamp: u64,
+    pub op: AuditOp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub channel: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester_pid: Option<u32>,
+    /// Remote user of signed authentication request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    pub decision: AuditDecision,
+    /// What decided, e.g. `confirmation`, `approver` or `approval 3`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(op: AuditOp, info: &RequestInfo, decision: AuditDecision) -> AuditRecord {
+        AuditRecord {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs()),
+            op,
+            fingerprint: None,
+            name: None,
+            channel: info.channel.to_string(),
+            requester: info.requester.as_ref()
+                .map(|requester| requester.description_long.clone()),
+            requester_pid: info.requester.as_ref().and_then(|requester| requester.pid),
+            destination: None,
+            decision,
+            rule: None,
+        }
+    }
+
+    pub fn key(mut self, name: Option<&str>, key: &PublicKey) -> AuditRecord {
+        self.name = name.map(str::to_string);
+        self.fingerprint = Some(key.fingerprint());
+        self
+    }
+
+    pub fn rule(mut self, rule: &str) -> AuditRecord {
+        self.rule = Some(rule.to_string());
+        self
+    }
+
+    pub fn destination(mut self, destination: Option<String>) -> AuditRecord {
+        self.destination = destination;
+        self
+    }
+}
+
+/// Append-only log of key usage, one JSON object per line. Log is rotated by size,
+/// `audit.log.1` being the most recent rotated file
+pub struct AuditLog {
+    file: Mutex<RotatingFile>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> AuditLog {
+        Self::with_rotation(path, DEFAULT_MAX_SIZE, DEFAULT_MAX_FILES)
+    }
+
+    /// Log is rotated once it reaches `max_size`, at most `max_files` rotated files are kept
+    pub fn with_rotation(path: PathBuf, max_size: u64, max_files: usize) -> AuditLog {
+        AuditLog { file: Mutex::new(RotatingFile::new(path, max_size, max_files)) }
+    }
+
+    pub fn record(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        let path = file.path().display().to_string();
+        file.write_all(line.as_bytes()).wrap_err(&format!("write {}", path))?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Reads records of log and its rotated files, oldest first. Malformed lines,
+    /// e.g. cut off by a crash, are skipped
+    pub fn read(path: &Path) -> Result<Vec<AuditRecord>> {
+        let mut paths: Vec<_> = (1..)
+            .map(|idx| rotated_path(path, idx))
+            .take_while(|path| path.exists())
+            .collect();
+        paths.reverse();
+        paths.push(path.to_path_buf());
+
+        let mut records = Vec::new();
+        for path in paths {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.wrap(&format!("open {}", path.display())).into()),
+            };
+            for line in BufReader::new(file).lines() {
+                if let Ok(record) = serde_json::from_str(&line?) {
+                    records.push(record);
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Formats timestamp as UTC `YYYY-MM-DDTHH:MM:SSZ`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, secs) = ((timestamp / 86400) as i64, timestamp % 86400);
+    // Civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600,
+            secs / 60 % 60, secs % 60)
+}
+
+/// Parses seconds since unix epoch or time relative to `now`, e.g. `30m`, `12h` or `7d` ago
+pub fn parse_time(s: &str, now: u64) -> Result<u64> {
+    let err = || Error::new(&format!("invalid time: {}, expected seconds since epoch or \
+                                      relative time like 12h", s));
+    if let Ok(timestamp) = s.parse() {
+        return Ok(timestamp);
+    }
+    let (index, unit) = s.char_indices().last().ok_or_else(err)?;
+    let amount: u64 = s[..index].parse().map_err(|_| err())?;
+    let unit = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return Err(err().into()),
+    };
+    Ok(now.saturating_sub(amount.checked_mul(unit).ok_or_else(err)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_times() {
+        let now = 1_000_000;
+        assert_eq!(parse_time("1700000000", now).unwrap(), 1700000000);
+        assert_eq!(parse_time("30s", now).unwrap(), now - 30);
+        assert_eq!(parse_time("30m", now).unwrap(), now - 1800);
+        assert_eq!(parse_time("2h", now).unwrap(), now - 7200);
+        assert_eq!(parse_time("7d", now).unwrap(), now - 604800);
+        assert_eq!(parse_time("100d", now).unwrap(), 0);
+    }
+
+    #[test]
+    fn invalid_times() {
+        for s in &["", "h", "12x", "-1h", "1.5h", "é", "1é", "éh", "18446744073709551615d"] {
+            assert!(parse_time(s, 1_000_000).is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::io::{Cursor, Read, Write};
+
+use log::debug;
+
+use wrapperrs::{Error, Result, ResultExt};
+
+pub use crate::agent::Identity;
+use crate::agent::{Agent, AgentRequest, AgentResponse, CONSTRAINT_EPHEMERAL, EXTENSION_QUERY,
+                   EXTENSION_RENAME, KeyConstraint};
+use crate::agent::wire::{ReadExt, WriteExt};
+use crate::key::{KeyPair, PublicKey};
+
+#[cfg(windows)]
+const DEFAULT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// Client side of ssh-agent protocol
+pub struct AgentClient {
+    stream: Box<dyn Stream>,
+}
+
+#[cfg(unix)]
+pub(crate) fn connect_path(path: &str) -> Result<Box<dyn Stream>> {
+    use std::os::unix::net::UnixStream;
+
+    Ok(Box::new(UnixStream::connect(path).wrap_err(&format!("connect to {}", path))?))
+}
+
+#[cfg(windows)]
+pub(crate) fn connect_path(path: &str) -> Result<Box<dyn Stream>> {
+    use std::fs::OpenOptions;
+
+    if path.starts_with(r"\\.\pipe\") {
+        let pipe = OpenOptions::new().read(true).write(true).open(path)
+            .wrap_err(&format!("connect to {}", path))?;
+        Ok(Box::new(pipe))
+    } else {
+        Ok(Box::new(uds_windows::UnixStream::connect(path)
+            .wrap_err(&format!("connect to {}", path))?))
+    }
+}
+
+#[cfg(all(unix, feature = "platform"))]
+fn connect_default() -> Result<Box<dyn Stream>> {
+    connect_path(crate::platform::unix_socket_path().to_str().unwrap())
+}
+
+#[cfg(all(unix, not(feature = "platform")))]
+fn connect_default() -> Result<Box<dyn Stream>> {
+    Err(Error::new("SSH_AUTH_SOCK is not set").into())
+}
+
+#[cfg(windows)]
+fn connect_default() -> Result<Box<dyn Stream>> {
+    let stream = connect_path(DEFAULT_PIPE);
+    #[cfg(feature = "platform")]
+    let stream = stream.or_else(|_| {
+        connect_path(crate::platform::unix_socket_path().to_str().unwrap())
+    });
+    stream
+}
+
+impl AgentClient {
+    /// Connects to running ssh-keys agent from `SSH_AUTH_SOCK`, falls back to default agent
+    /// socket, on windows OpenSSH named pipe is tried first. Other agents, e.g. OpenSSH
+    /// ssh-agent in `SSH_AUTH_SOCK`, are skipped, so that keys are never sent to them
+    pub fn connect() -> Result<AgentClient> {
+        Ok(AgentClient::connect_running()?.ok_or(Error::new("agent isn't running"))?)
+    }
+
+    /// Same as [`AgentClient::connect`], but `None` if there is no agent to connect to.
+    /// Agent that accepts connection but can't be used, e.g. because it's locked, is an error,
+    /// so that callers don't fall back to changing config behind its back
+    pub fn connect_running() -> Result<Option<AgentClient>> {
+        if let Ok(path) = std::env::var("SSH_AUTH_SOCK") {
+            if !path.is_empty() {
+                match connect_path(&path) {
+                    Ok(stream) => {
+                        let mut client = AgentClient { stream };
+                        if client.is_ssh_keys() {
+                            return Ok(Some(client));
+                        }
+                        debug!("agent at {} isn't ssh-keys", path);
+                    }
+                    Err(err) => debug!("{}", err),
+                }
+            }
+        }
+
+        let mut client = match connect_default() {
+            Ok(stream) => AgentClient { stream },
+            Err(err) => {
+                debug!("{}", err);
+                return Ok(None);
+            }
+        };
+        if !client.is_ssh_keys() {
+            return Err(Error::new("running agent is locked or isn't ssh-keys").into());
+        }
+        Ok(Some(client))
+    }
+
+    /// Whether agent supports ssh-keys extensions
+    fn is_ssh_keys(&mut self) -> bool {
+        let names = match self.extension(EXTENSION_QUERY, &[]) {
+            Ok(AgentResponse::ExtensionSuccess(names)) => names,
+            _ => return false,
+        };
+        let mut names = Cursor::new(names);
+        while let Ok(name) = names.read_string() {
+            if name == EXTENSION_RENAME.as_bytes() {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn connect_to(path: &str) -> Result<AgentClient> {
+        Ok(AgentClient { stream: connect_path(path)? })
+    }
+
+    fn request(&mut self, req: &AgentRequest) -> Result<AgentResponse> {
+        self.stream.write_string(req.encode()?).wrap_err("write request")?;
+        let resp = self.stream.read_string().wrap_err("read response")?;
+        Ok(AgentResponse::decode(&resp).wrap_err("decode response")?)
+    }
+
+    /// Sends already encoded request and returns response as is, used for relaying
+    pub fn request_raw(&mut self, req: &[u8]) -> Result<Vec<u8>> {
+        self.stream.write_string(req).wrap_err("write request")?;
+        self.stream.flush()?;
+        Ok(self.stream.read_string().wrap_err("read response")?)
+    }
+
+    fn simple_request(&mut self, req: &AgentRequest) -> Result<()> {
+        match self.request(req)? {
+            AgentResponse::Success => Ok(()),
+            _ => Err(Error::new("agent returned failure").into()),
+        }
+    }
+
+    pub fn request_identities(&mut self) -> Result<Vec<Identity>> {
+        match self.request(&AgentRequest::RequestIdentities)? {
+            AgentResponse::IdentitiesAnswer(identities) => Ok(identities),
+            _ => Err(Error::new("agent refused to list identities").into()),
+        }
+    }
+
+    pub fn sign(&mut self, key: &PublicKey, data: &[u8], flags: u32) -> Result<Vec<u8>> {
+        let req = AgentRequest::SignRequest { key: key.clone(), data: data.to_vec(), flags };
+        match self.request(&req)? {
+            AgentResponse::SignResponse(signature) => Ok(signature),
+            _ => Err(Error::new("agent refused to sign").into()),
+        }
+    }
+
+    pub fn add_identity(&mut self, key_pair: KeyPair) -> Result<()> {
+        self.simple_request(&AgentRequest::AddIdentity { key_pair })
+    }
+
+    /// Adds key which agent keeps in memory only
+    pub fn add_ephemeral_identity(&mut self, key_pair: KeyPair) -> Result<()> {
+        self.simple_request(&AgentRequest::AddIdConstrained {
+            key_pair,
+            constraints: vec![KeyConstraint::Extension {
+                name: CONSTRAINT_EPHEMERAL.to_string(),
+                data: Vec::new(),
+            }],
+        })
+    }
+
+    pub fn remove_identity(&mut self, key: &PublicKey) -> Result<()> {
+        self.simple_request(&AgentRequest::RemoveIdentity { key: key.clone() })
+    }
+
+    pub fn remove_all_identities(&mut self) -> Result<()> {
+        self.simple_request(&AgentRequest::RemoveAllIdentities)
+    }
+
+    pub fn lock(&mut self, passphrase: &str) -> Result<()> {
+        self.simple_request(&AgentRequest::Lock { passphrase: passphrase.to_string() })
+    }
+
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        self.simple_request(&AgentRequest::Unlock { passphrase: passphrase.to_string() })
+    }
+
+    pub fn extension(&mut self, name: &str, contents: &[u8]) -> Result<AgentResponse> {
+        self.request(&AgentRequest::Extension {
+            name: name.to_string(),
+            contents: contents.to_vec(),
+        })
+    }
+
+    pub fn rename(&mut self, key: &PublicKey, name: &str) -> Result<()> {
+        let mut contents = Vec::new();
+        contents.write_string(key.encode())?;
+        contents.write_string(name)?;
+        self.extension_request(EXTENSION_RENAME, &contents)
+    }
+
+    fn extension_request(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        match self.extension(name, contents)? {
+            AgentResponse::Success | AgentResponse::ExtensionSuccess(_) => Ok(()),
+            _ => Err(Error::new(&format!("agent rejected {}", name)).into()),
+        }
+    }
+}
+
+/// Keys of running agent, or keys from config file if agent isn't running
+pub fn identities(agent: &Agent) -> Result<Vec<Identity>> {
+    match AgentClient::connect_running()? {
+        Some(mut client) => Ok(client.request_identities().wrap_err("list agent keys")?),
+        None => Ok(agent.config()
+            .keys
+            .iter()
+            .map(|key_pair| Identity {
+                key: key_pair.public().clone(),
+                comment: key_pair.name().to_string(),
+            })
+            .collect()),
+    }
+}
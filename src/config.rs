@@ -1,58 +1,107 @@
-use std::fs::File;
+use std::fs::{File, rename};
 use std::io::{ErrorKind, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 use wrapperrs::Result;
 
 use crate::key::KeyPair;
-use crate::platform::config_dir;
+use crate::logging::LoggingConfig;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct Config {
+    /// File config was loaded from, config without path is never saved
+    #[serde(skip)]
+    path: Option<PathBuf>,
     #[serde(
     deserialize_with = "crate::key::deserialize_key_pairs",
     serialize_with = "crate::key::serialize_key_pairs"
     )]
     pub keys: Vec<KeyPair>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config { keys: Vec::new() }
+impl Config {
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
     }
-}
 
-impl Config {
     pub fn save(&self) -> Result<()> {
-        let mut file = File::create(config_file())?;
-        file.write_all(&toml::to_string_pretty(self)?.as_bytes())?;
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // Write to temporary file first, so config is never left half-written
+        let tmp_file = path.with_extension("toml.tmp");
+        {
+            let mut file = File::create(&tmp_file)?;
+            file.write_all(toml::to_string_pretty(self)?.as_bytes())?;
+            file.sync_all()?;
+        }
+        rename(&tmp_file, path)?;
         Ok(())
     }
 
+    /// Whether both configs are saved the same, e.g. when they differ only in ephemeral keys
+    pub fn same_on_disk(&self, other: &Config) -> Result<bool> {
+        Ok(toml::to_string_pretty(self)? == toml::to_string_pretty(other)?)
+    }
+
+    /// Reloads config from file, ephemeral keys are kept
     pub fn reload(&mut self) -> Result<()> {
-        *self = load_config()?;
+        if let Some(path) = self.path.clone() {
+            let mut config = load_config(&path)?;
+            for key_pair in self.keys.drain(..).filter(KeyPair::is_ephemeral) {
+                if config.keys.iter().all(|key| key.public() != key_pair.public()) {
+                    config.keys.push(key_pair);
+                }
+            }
+            *self = config;
+        }
         Ok(())
     }
 }
 
-fn config_file() -> PathBuf {
-    config_dir().join("config.toml")
-}
-
-pub fn load_config() -> Result<Config> {
-    match File::open(config_file()) {
+pub fn load_config(path: &Path) -> Result<Config> {
+    let mut config = match File::open(path) {
         Ok(mut file) => {
             let mut buf = Vec::new();
             file.read_to_end(&mut buf)?;
-            Ok(toml::from_slice(&buf)?)
+            toml::from_slice(&buf)?
         }
         Err(err) => {
             if let ErrorKind::NotFound = err.kind() {
-                Ok(Config::default())
+                Config::default()
             } else {
-                Err(err.into())
+                return Err(err.into());
             }
         }
+    };
+    config.path = Some(path.to_path_buf());
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::PrivateKey;
+
+    use super::*;
+
+    fn key_pair(name: &str) -> KeyPair {
+        KeyPair::new(PrivateKey::generate_ed25519().unwrap(), name.to_string()).unwrap()
+    }
+
+    #[test]
+    fn ephemeral_keys_not_saved() {
+        let config = Config::default();
+        let mut changed = config.clone();
+        let mut ephemeral = key_pair("ephemeral");
+        ephemeral.set_ephemeral(true);
+        changed.keys.push(ephemeral);
+        assert!(changed.same_on_disk(&config).unwrap());
+        changed.keys.push(key_pair("saved"));
+        assert!(!changed.same_on_disk(&config).unwrap());
     }
 }
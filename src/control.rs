@@ -0,0 +1,495 @@
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
+                AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use wrapperrs::{Error, ErrorExt, Result, ResultExt};
+
+use crate::agent::{Agent, AgentEvent, Approval, ApproverMessage, ApproverSession, Decision,
+                   PendingRequest, RequestInfo};
+use crate::audit::{AuditDecision, AuditOp, AuditRecord};
+use crate::client::{connect_path, Stream};
+use crate::config::Config;
+use crate::key::{FingerprintHash, PublicKey};
+use crate::logging::Secret;
+use crate::server::{Listener, Service};
+use crate::stats::KeyStats;
+
+pub const CONTROL_VERSION: u32 = 1;
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Control connections are accepted only from the agent's user
+const CONTROL_INFO: RequestInfo = RequestInfo { channel: "Control", requester: None };
+
+#[cfg(windows)]
+pub const CONTROL_PIPE: &str = r"\\.\pipe\ssh-keys-control";
+
+/// Management protocol of running agent: one JSON object per line, every request and
+/// response carries protocol version
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ControlRequest {
+    pub version: u32,
+    #[serde(flatten)]
+    pub command: ControlCommand,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ControlCommand {
+    List,
+    /// `key` is key name or fingerprint
+    Remove { key: String },
+    Rename { key: String, name: String },
+    Reload,
+    Lock { passphrase: Secret<String> },
+    Unlock { passphrase: Secret<String> },
+    Approvals,
+    Revoke { id: u64 },
+    /// Connection receives events afterwards, no more requests are read
+    Subscribe,
+    /// Connection receives pending sign requests afterwards and may only send `decide`
+    AttachApprover,
+    /// Decision on pending request, `remember_secs` approves the same requester for that long
+    Decide {
+        id: u64,
+        approve: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remember_secs: Option<u64>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeyInfo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub key_type: String,
+    pub bits: u32,
+    pub fingerprint: String,
+    pub ephemeral: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<KeyStats>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ControlResponse {
+    pub version: u32,
+    #[serde(flatten)]
+    pub body: ResponseBody,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseBody {
+    Ok,
+    Error { message: String },
+    Keys { keys: Vec<KeyInfo> },
+    Approvals { approvals: Vec<Approval> },
+    Event { event: AgentEvent },
+    Pending { request: PendingRequest },
+    /// Pending request wasn't decided in time and was denied
+    Expired { id: u64 },
+}
+
+fn find_key(config: &Config, pattern: &str) -> Result<PublicKey> {
+    config.keys.iter()
+        .map(|key_pair| key_pair.public())
+        .zip(config.keys.iter().map(|key_pair| key_pair.name()))
+        .find(|(key, name)| {
+            *name == pattern
+                || key.fingerprint_with(FingerprintHash::SHA256) == pattern
+                || key.fingerprint_with(FingerprintHash::MD5) == pattern
+        })
+        .map(|(key, _)| key.clone())
+        .ok_or(Error::new(&format!("key not found: {}", pattern)).into())
+}
+
+fn execute(agent: &Agent, command: ControlCommand) -> Result<ResponseBody> {
+    use ControlCommand::*;
+
+    Ok(match command {
+        List => ResponseBody::Keys {
+            keys: agent.config().keys.iter().map(|key_pair| {
+                let public = key_pair.public();
+                KeyInfo {
+                    name: key_pair.name().to_string(),
+                    key_type: public.key_type().to_string(),
+                    bits: public.bits(),
+                    fingerprint: public.fingerprint(),
+                    ephemeral: key_pair.is_ephemeral(),
+                    usage: agent.key_stats(public),
+                }
+            }).collect(),
+        },
+        Remove { key } => {
+            let key = find_key(&agent.config(), &key)?;
+            agent.remove_key(&key)?;
+            agent.audit(AuditRecord::new(AuditOp::Remove, &CONTROL_INFO, AuditDecision::Allowed)
+                .key(None, &key));
+            ResponseBody::Ok
+        }
+        Rename { key, name } => {
+            agent.rename_key(&find_key(&agent.config(), &key)?, name)?;
+            ResponseBody::Ok
+        }
+        Reload => {
+            agent.reload_config()?;
+            ResponseBody::Ok
+        }
+        Lock { passphrase } if agent.lock(&passphrase.0) => {
+            agent.audit(AuditRecord::new(AuditOp::Lock, &CONTROL_INFO, AuditDecision::Allowed));
+            ResponseBody::Ok
+        }
+        Lock { .. } => return Err(Error::new("agent is locked already").into()),
+        Unlock { passphrase } if agent.unlock(&passphrase.0) => {
+            agent.audit(AuditRecord::new(AuditOp::Unlock, &CONTROL_INFO, AuditDecision::Allowed)
+                .rule("passphrase"));
+            ResponseBody::Ok
+        }
+        Unlock { .. } => {
+            agent.audit(AuditRecord::new(AuditOp::Unlock, &CONTROL_INFO, AuditDecision::Denied)
+                .rule("passphrase"));
+            return Err(Error::new("agent isn't locked or passphrase is wrong").into());
+        }
+        Approvals => ResponseBody::Approvals { approvals: agent.approvals() },
+        Revoke { id } if agent.revoke_approval(id) => ResponseBody::Ok,
+        Revoke { id } => return Err(Error::new(&format!("approval not found: {}", id)).into()),
+        Subscribe | AttachApprover => {
+            return Err(Error::new("subscription isn't a simple request").into());
+        }
+        Decide { .. } => return Err(Error::new("approver isn't attached").into()),
+    })
+}
+
+fn respond(agent: &Agent, command: ControlCommand) -> ResponseBody {
+    execute(agent, command).unwrap_or_else(|err| ResponseBody::Error { message: err.to_string() })
+}
+
+/// Reads line of at most `MAX_LINE_LEN`, `None` on end of stream
+async fn read_line<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    let len = r.take(MAX_LINE_LEN as u64 + 1).read_line(&mut line).await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if len > MAX_LINE_LEN {
+        return Err(Error::new("request too long").into());
+    }
+    Ok(Some(line))
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(w: &mut W, body: ResponseBody) -> Result<()> {
+    let mut line = serde_json::to_string(&ControlResponse { version: CONTROL_VERSION, body })?;
+    line.push('\n');
+    w.write_all(line.as_bytes()).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+/// Serves one management connection until it's closed or service shuts down
+pub async fn serve_control<S>(agent: Arc<Agent>, stream: S, mut shutdown: watch::Receiver<bool>)
+                              -> Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = tokio::io::BufReader::new(reader);
+    loop {
+        let line = tokio::select! {
+            line = read_line(&mut reader) => match line? {
+                Some(line) => line,
+                None => return Ok(()),
+            },
+            _ = shutdown.changed() => return Ok(()),
+        };
+
+        let request = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                let message = format!("invalid request: {}", err);
+                write_response(&mut writer, ResponseBody::Error { message }).await?;
+                continue;
+            }
+        };
+        if request.version != CONTROL_VERSION {
+            let message = format!("unsupported version {}, agent supports {}", request.version,
+                                  CONTROL_VERSION);
+            write_response(&mut writer, ResponseBody::Error { message }).await?;
+            continue;
+        }
+        if let ControlCommand::Subscribe = request.command {
+            let mut events = agent.subscribe();
+            write_response(&mut writer, ResponseBody::Ok).await?;
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    // Client closed connection
+                    _ = read_line(&mut reader) => return Ok(()),
+                    _ = shutdown.changed() => return Ok(()),
+                };
+                match event {
+                    Ok(event) => write_response(&mut writer, ResponseBody::Event { event }).await?,
+                    Err(RecvError::Lagged(skipped)) => warn!("{} events dropped", skipped),
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+
+        if let ControlCommand::AttachApprover = request.command {
+            let session = match agent.attach_approver() {
+                Some(session) => session,
+                None => {
+                    let message = "another approver is attached".to_string();
+                    write_response(&mut writer, ResponseBody::Error { message }).await?;
+                    continue;
+                }
+            };
+            write_response(&mut writer, ResponseBody::Ok).await?;
+            return serve_approver(&agent, session, reader, writer, shutdown).await;
+        }
+
+        let command_agent = agent.clone();
+        let body = tokio::task::spawn_blocking(move || respond(&command_agent, request.command))
+            .await?;
+        write_response(&mut writer, body).await?;
+    }
+}
+
+/// Forwards pending requests to approver and its decisions to agent
+async fn serve_approver<R, W>(agent: &Agent, mut session: ApproverSession, mut reader: R,
+                              mut writer: W, mut shutdown: watch::Receiver<bool>) -> Result<()>
+    where R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin
+{
+    loop {
+        // Response is written after select, its output holds the non-Send read error
+        let body = tokio::select! {
+            message = session.recv() => match message {
+                Some(ApproverMessage::Pending(request)) => ResponseBody::Pending { request },
+                Some(ApproverMessage::Expired(id)) => ResponseBody::Expired { id },
+                None => return Ok(()),
+            },
+            line = read_line(&mut reader) => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => return Ok(()),
+                };
+                let message = match serde_json::from_str::<ControlRequest>(&line) {
+                    Ok(ControlRequest { command: ControlCommand::Decide { id, approve,
+                                                                          remember_secs }, .. })
+                    => {
+                        let decision = Decision {
+                            approve,
+                            remember: remember_secs.map(Duration::from_secs),
+                        };
+                        if agent.decide(id, decision) {
+                            continue;
+                        }
+                        format!("request {} isn't pending", id)
+                    }
+                    Ok(_) => "approver may only send decide".to_string(),
+                    Err(err) => format!("invalid request: {}", err),
+                };
+                ResponseBody::Error { message }
+            }
+            _ = shutdown.changed() => return Ok(()),
+        };
+        write_response(&mut writer, body).await?;
+    }
+}
+
+/// Accepts management connections until service shuts down
+pub async fn run_control<L: Listener>(service: Arc<Service>, mut listener: L) -> Result<()> {
+    while let Some((stream, info)) = service.accept(&mut listener).await {
+        debug!("control connection channel={}", info.channel);
+        let agent = service.agent().clone();
+        let shutdown = service.shutdown_receiver();
+        tokio::spawn(async move {
+            if let Err(err) = serve_control(agent, stream, shutdown).await {
+                error!("{}", err.wrap("control connection error"));
+            }
+        });
+    }
+    Ok(())
+}
+
+/// `SSH_KEYS_CONTROL`, or control socket next to agent socket
+#[cfg(unix)]
+fn default_control_path() -> Result<String> {
+    use std::path::PathBuf;
+
+    if let Ok(path) = std::env::var("SSH_KEYS_CONTROL") {
+        return Ok(path);
+    }
+    let socket_path = match std::env::var("SSH_AUTH_SOCK") {
+        Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => None,
+    };
+    #[cfg(feature = "platform")]
+    let socket_path = socket_path
+        .filter(|path| path.with_extension("ctl").exists())
+        .or_else(|| Some(crate::platform::unix_socket_path()));
+    let socket_path = socket_path.ok_or(Error::new("SSH_KEYS_CONTROL is not set"))?;
+    Ok(socket_path.with_extension("ctl").to_string_lossy().into_owned())
+}
+
+#[cfg(windows)]
+fn default_control_path() -> Result<String> {
+    Ok(std::env::var("SSH_KEYS_CONTROL").unwrap_or_else(|_| CONTROL_PIPE.to_string()))
+}
+
+/// Blocking client of management protocol
+pub struct ControlClient {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl ControlClient {
+    pub fn connect() -> Result<ControlClient> {
+        Self::connect_to(&default_control_path()?)
+    }
+
+    pub fn connect_to(path: &str) -> Result<ControlClient> {
+        Ok(ControlClient { stream: BufReader::new(connect_path(path)?) })
+    }
+
+    /// Reads next message, `Error` body isn't turned into error
+    pub fn next_message(&mut self) -> Result<ResponseBody> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(Error::new("agent closed connection").into());
+        }
+        let response: ControlResponse = serde_json::from_str(&line)
+            .wrap_err("decode response")?;
+        Ok(response.body)
+    }
+
+    fn read_response(&mut self) -> Result<ResponseBody> {
+        match self.next_message()? {
+            ResponseBody::Error { message } => Err(Error::new(&message).into()),
+            body => Ok(body),
+        }
+    }
+
+    /// Sends command without waiting for response
+    pub fn send(&mut self, command: ControlCommand) -> Result<()> {
+        let mut line = serde_json::to_string(&ControlRequest {
+            version: CONTROL_VERSION,
+            command,
+        })?;
+        line.push('\n');
+        let stream = self.stream.get_mut();
+        stream.write_all(line.as_bytes()).wrap_err("write request")?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    pub fn request(&mut self, command: ControlCommand) -> Result<ResponseBody> {
+        self.send(command)?;
+        self.read_response()
+    }
+
+    /// Subscribes to events, which are then read with `next_event`
+    pub fn subscribe(&mut self) -> Result<()> {
+        self.request(ControlCommand::Subscribe).map(|_| ())
+    }
+
+    /// Attaches as approver, pending requests are then read with `next_message`
+    /// and answered with `decide`
+    pub fn attach_approver(&mut self) -> Result<()> {
+        self.request(ControlCommand::AttachApprover).map(|_| ())
+    }
+
+    /// Agent responds only if request isn't pending anymore
+    pub fn decide(&mut self, id: u64, approve: bool, remember: Option<Duration>) -> Result<()> {
+        self.send(ControlCommand::Decide {
+            id,
+            approve,
+            remember_secs: remember.map(|duration| duration.as_secs()),
+        })
+    }
+
+    pub fn next_event(&mut self) -> Result<AgentEvent> {
+        match self.read_response()? {
+            ResponseBody::Event { event } => Ok(event),
+            _ => Err(Error::new("unexpected response").into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Parses `line` as `T` and serializes it back, comparing JSON values so that field
+    /// order doesn't matter
+    fn round_trip<T: Serialize + for<'de> Deserialize<'de>>(line: &str) -> T {
+        let parsed: T = serde_json::from_str(line).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(),
+                   serde_json::from_str::<Value>(line).unwrap());
+        parsed
+    }
+
+    #[test]
+    fn requests() {
+        for line in &[
+            r#"{"version":1,"op":"list"}"#,
+            r#"{"version":1,"op":"remove","key":"work"}"#,
+            r#"{"version":1,"op":"rename","key":"old","name":"new"}"#,
+            r#"{"version":1,"op":"reload"}"#,
+            r#"{"version":1,"op":"lock","passphrase":"secret"}"#,
+            r#"{"version":1,"op":"unlock","passphrase":"secret"}"#,
+            r#"{"version":1,"op":"approvals"}"#,
+            r#"{"version":1,"op":"revoke","id":3}"#,
+            r#"{"version":1,"op":"subscribe"}"#,
+            r#"{"version":1,"op":"attach_approver"}"#,
+            r#"{"version":1,"op":"decide","id":1,"approve":true,"remember_secs":600}"#,
+            r#"{"version":1,"op":"decide","id":1,"approve":false}"#,
+        ] {
+            let request: ControlRequest = round_trip(line);
+            assert_eq!(request.version, CONTROL_VERSION);
+        }
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"version":1,"op":"lock","passphrase":"secret"}"#).unwrap();
+        assert!(!format!("{:?}", request).contains("secret"));
+    }
+
+    #[test]
+    fn invalid_requests() {
+        for line in &[
+            r#"{"op":"list"}"#,
+            r#"{"version":1,"op":"unknown"}"#,
+            r#"{"version":1,"op":"remove"}"#,
+            r#"{"version":1,"op":"revoke","id":"3"}"#,
+        ] {
+            assert!(serde_json::from_str::<ControlRequest>(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn responses() {
+        for line in &[
+            r#"{"version":1,"type":"ok"}"#,
+            r#"{"version":1,"type":"error","message":"key not found: x"}"#,
+            r#"{"version":1,"type":"keys","keys":[{"name":"work","type":"ssh-ed25519",
+                "bits":256,"fingerprint":"SHA256:abc","ephemeral":false,
+                "usage":{"created":1,"last_used":2,"signatures":1,
+                         "recent":[{"timestamp":2,"requester":"ssh"}]}}]}"#,
+            r#"{"version":1,"type":"approvals","approvals":[{"id":1,"key_name":"work",
+                "fingerprint":"SHA256:abc","requester":"ssh","expires":100}]}"#,
+            r#"{"version":1,"type":"event","event":{"event":"key_removed",
+                "fingerprint":"SHA256:abc"}}"#,
+            r#"{"version":1,"type":"event","event":{"event":"sign_request","name":"work",
+                "fingerprint":"SHA256:abc","channel":"Unix","approved":true}}"#,
+            r#"{"version":1,"type":"pending","request":{"id":1,"key_name":"work",
+                "fingerprint":"SHA256:abc","channel":"Unix","user":"git"}}"#,
+            r#"{"version":1,"type":"expired","id":1}"#,
+        ] {
+            let response: ControlResponse = round_trip(line);
+            assert_eq!(response.version, CONTROL_VERSION);
+        }
+    }
+}
@@ -4,23 +4,22 @@ use std::path::Path;
 
 use byteorder::WriteBytesExt;
 use clap::ArgMatches;
-use data_encoding::BASE64;
 use ssh2::{OpenFlags, OpenType, Session};
 use wrapperrs::{Error, Result, ResultExt};
 
-use crate::agent::Agent;
+use ssh_keys::agent::Agent;
+use ssh_keys::client::identities;
+use ssh_keys::key::{AuthorizedKey, AuthorizedKeys};
 
 pub fn copy_id(agent: &Agent, opts: &ArgMatches) -> Result<()> {
     let key_name = opts.value_of("key").unwrap();
-    let key = agent
-        .config()
-        .keys
-        .iter()
-        .find(|k| k.name() == key_name)
+    let key = identities(agent)?
+        .into_iter()
+        .find(|identity| identity.comment == key_name)
         .ok_or(Error::new("key not found"))?;
 
     let (user, host) = {
-        let split: Vec<&str> = opts.value_of("host").unwrap().split("@").collect();
+        let split: Vec<&str> = opts.value_of("username@host").unwrap().split("@").collect();
         if let &[user, host] = split.as_slice() {
             (user, host)
         } else {
@@ -49,7 +48,7 @@ pub fn copy_id(agent: &Agent, opts: &ArgMatches) -> Result<()> {
 
     let sftp = sess.sftp()?;
     let ssh_dir = home.join(".ssh");
-    if let Err(_) = sftp.stat(&ssh_dir) {
+    if sftp.stat(&ssh_dir).is_err() {
         sftp.mkdir(&ssh_dir, 0o700)?;
     }
 
@@ -66,27 +65,19 @@ pub fn copy_id(agent: &Agent, opts: &ArgMatches) -> Result<()> {
         file.read_to_string(&mut str)?;
         file.seek(SeekFrom::End(0))?;
     }
+    let authorized_keys = str.parse::<AuthorizedKeys>()?;
 
-    let public_key = key.public();
-    let public_key_b64 = BASE64.encode(&public_key.encode());
-
-    if !str.contains(&public_key_b64) {
+    let public_key = &key.key;
+    if !authorized_keys.contains(public_key) {
         if !str.is_empty() && !str.ends_with("\n") {
-            file.write_u8('\n' as u8)?;
+            file.write_u8(b'\n')?;
         }
 
-        file.write_all(
-            format!(
-                "{} {} {}\n",
-                public_key.key_type(),
-                public_key_b64,
-                key.name()
-            )
-                .as_bytes(),
-        )?;
-        println!("Key successfully added");
+        let entry = AuthorizedKey::new(public_key.clone(), Some(key.comment.clone()));
+        file.write_all(format!("{}\n", entry).as_bytes())?;
+        println!("Key successfully added: {} {}", key.comment, public_key.fingerprint());
     } else {
-        println!("Key exists");
+        println!("Key exists: {} {}", key.comment, public_key.fingerprint());
     };
 
     Ok(())
@@ -0,0 +1,174 @@
+use clap::ArgMatches;
+use ssh_keys::Agent;
+use wrapperrs::Result;
+
+#[cfg(unix)]
+pub use self::unix::daemon;
+
+#[cfg(windows)]
+pub fn daemon(_agent: Agent, _opts: &ArgMatches) -> Result<()> {
+    Err(wrapperrs::Error::new("agent subcommand is only supported on unix").into())
+}
+
+/// ssh-agent compatible daemon, prints environment for `eval`
+#[cfg(unix)]
+mod unix {
+    use std::fs::{File, OpenOptions, read_to_string, remove_file};
+    use std::io;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use log::info;
+    use ssh_keys::platform::{bind_socket, control_socket_path, serve_listeners, ServeOptions,
+                             unix_socket_path};
+    use ssh_keys::platform::systemd::listen_fds;
+    use ssh_keys::utils::Finally;
+    use wrapperrs::{Error, ErrorExt, ResultExt};
+
+    use crate::load::load_keys;
+
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    enum Shell {
+        Sh,
+        Csh,
+    }
+
+    impl Shell {
+        fn from_opts(opts: &ArgMatches) -> Shell {
+            if opts.is_present("csh") {
+                Shell::Csh
+            } else if opts.is_present("sh") {
+                Shell::Sh
+            } else {
+                match std::env::var("SHELL") {
+                    Ok(shell) if shell.ends_with("csh") => Shell::Csh,
+                    _ => Shell::Sh,
+                }
+            }
+        }
+
+        fn set_var(self, name: &str, value: &str) -> String {
+            match self {
+                Shell::Sh => format!("{}={}; export {};", name, value, name),
+                Shell::Csh => format!("setenv {} {};", name, value),
+            }
+        }
+
+        fn unset_var(self, name: &str) -> String {
+            match self {
+                Shell::Sh => format!("unset {};", name),
+                Shell::Csh => format!("unsetenv {};", name),
+            }
+        }
+    }
+
+    fn pid_file(socket_path: &Path) -> PathBuf {
+        socket_path.with_extension("pid")
+    }
+
+    fn print_env(shell: Shell, socket_path: &Path, pid: u32) {
+        println!("{}", shell.set_var("SSH_AUTH_SOCK", &socket_path.to_string_lossy()));
+        println!("{}", shell.set_var("SSH_AGENT_PID", &pid.to_string()));
+        println!("echo Agent pid {};", pid);
+        let _ = io::stdout().flush();
+    }
+
+    /// Detaches from terminal, returns child pid in parent and `None` in child
+    fn daemonize() -> Result<Option<u32>> {
+        // Output buffered so far shouldn't be written twice
+        io::stdout().flush()?;
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error().wrap("fork").into()),
+            0 => {}
+            pid => return Ok(Some(pid as u32)),
+        }
+
+        if unsafe { libc::setsid() } == -1 {
+            return Err(io::Error::last_os_error().wrap("setsid").into());
+        }
+        std::env::set_current_dir("/")?;
+        let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+        for fd in 0..3 {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+                return Err(io::Error::last_os_error().wrap("redirect stdio").into());
+            }
+        }
+        Ok(None)
+    }
+
+    fn kill(shell: Shell, socket_path: &Path) -> Result<()> {
+        let pid = match std::env::var("SSH_AGENT_PID") {
+            Ok(pid) if !pid.is_empty() => pid,
+            _ => read_to_string(pid_file(socket_path))
+                .wrap_err("SSH_AGENT_PID is not set and pid file can't be read")?,
+        };
+        let pid: libc::pid_t = pid.trim().parse()
+            .map_err(|_| Error::new(&format!("invalid agent pid: {}", pid.trim())))?;
+
+        if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+            return Err(io::Error::last_os_error().wrap(&format!("kill agent pid {}", pid)).into());
+        }
+        println!("{}", shell.unset_var("SSH_AUTH_SOCK"));
+        println!("{}", shell.unset_var("SSH_AGENT_PID"));
+        println!("echo Agent pid {} killed;", pid);
+        Ok(())
+    }
+
+    pub fn daemon(agent: Agent, opts: &ArgMatches) -> Result<()> {
+        let shell = Shell::from_opts(opts);
+        let mut socket_path = opts.value_of("address").map(PathBuf::from)
+            .unwrap_or_else(unix_socket_path);
+        if opts.is_present("kill") {
+            return kill(shell, &socket_path);
+        }
+        let mut options = ServeOptions {
+            socket_path: None,
+            idle_exit: opts.value_of("idle-exit")
+                .map(|secs| secs.parse().map(Duration::from_secs))
+                .transpose()
+                .map_err(|_| Error::new("invalid --idle-exit"))?,
+            ..ServeOptions::default()
+        };
+
+        for key_pair in load_keys(opts)? {
+            agent.add_key(key_pair)?;
+        }
+
+        // Socket is owned by systemd when activated, otherwise it's bound before fork,
+        // so that printed socket is ready when parent exits
+        let mut listeners = listen_fds()?;
+        if let Some(path) = listeners.first()
+            .and_then(|listener| listener.local_addr().ok())
+            .and_then(|addr| addr.as_pathname().map(Path::to_path_buf)) {
+            socket_path = path;
+        }
+        if listeners.is_empty() {
+            listeners.push(bind_socket(&socket_path)?);
+            options.socket_path = Some(socket_path.clone());
+        }
+        options.control_path = Some(control_socket_path(&socket_path));
+        let pid = if opts.is_present("foreground") || opts.is_present("debug") {
+            let pid = std::process::id();
+            print_env(shell, &socket_path, pid);
+            pid
+        } else {
+            if let Some(pid) = daemonize()? {
+                print_env(shell, &socket_path, pid);
+                return Ok(());
+            }
+            std::process::id()
+        };
+
+        let pid_file = pid_file(&socket_path);
+        writeln!(File::create(&pid_file).wrap_err("create pid file")?, "{}", pid)?;
+        let _pid_file = Finally::new(|| {
+            let _ = remove_file(&pid_file);
+        });
+        info!("Started pid={}", pid);
+        serve_listeners(agent, listeners, &options)
+    }
+}
@@ -0,0 +1,139 @@
+use clap::ArgMatches;
+use ssh_keys::Agent;
+use wrapperrs::Result;
+
+#[cfg(unix)]
+pub use self::unix::exec;
+
+#[cfg(windows)]
+pub fn exec(_agent: &Agent, _opts: &ArgMatches) -> Result<()> {
+    Err(wrapperrs::Error::new("exec subcommand is only supported on unix").into())
+}
+
+/// Runs command with a private agent, which holds only selected keys and lives as long
+/// as the command
+#[cfg(unix)]
+mod unix {
+    use std::ffi::{CString, OsString};
+    use std::fs::remove_dir_all;
+    use std::io;
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::sync::Arc;
+
+    use log::{error, info};
+    use ssh_keys::audit::AuditLog;
+    use ssh_keys::config::Config;
+    use ssh_keys::platform;
+    use ssh_keys::server::{ConnectionLimits, Service, UnixSocketListener};
+    use ssh_keys::utils::Finally;
+    use tokio::signal::unix::{signal, SignalKind};
+    use wrapperrs::{ErrorExt, ResultExt};
+
+    use crate::load::load_keys;
+    use crate::manage::find_key;
+
+    use super::*;
+
+    /// Forwards termination signals to child. SIGINT is ignored, terminal sends it
+    /// to the child anyway
+    async fn forward_signals(pid: libc::pid_t) -> Result<()> {
+        let mut terminate = signal(SignalKind::terminate()).wrap_err("SIGTERM")?;
+        let mut hangup = signal(SignalKind::hangup()).wrap_err("SIGHUP")?;
+        let mut interrupt = signal(SignalKind::interrupt()).wrap_err("SIGINT")?;
+        loop {
+            let signum = tokio::select! {
+                _ = terminate.recv() => libc::SIGTERM,
+                _ = hangup.recv() => libc::SIGHUP,
+                _ = interrupt.recv() => continue,
+            };
+            unsafe { libc::kill(pid, signum) };
+        }
+    }
+
+    /// Creates directory with unpredictable name only accessible by current user
+    fn create_temp_dir() -> Result<PathBuf> {
+        let template = std::env::temp_dir().join(format!("{}-exec-XXXXXX", ssh_keys::NAME));
+        let mut template = CString::new(template.into_os_string().into_vec())?
+            .into_bytes_with_nul();
+        if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+            return Err(io::Error::last_os_error().wrap("create socket dir").into());
+        }
+        template.pop();
+        Ok(PathBuf::from(OsString::from_vec(template)))
+    }
+
+    fn run(agent: &Agent, opts: &ArgMatches) -> Result<i32> {
+        let config = agent.config();
+        let mut private_config = Config::default();
+        private_config.keys = load_keys(opts)?;
+        for pattern in opts.values_of("key").into_iter().flatten() {
+            let (name, _) = find_key(
+                config.keys.iter().map(|key_pair| (key_pair.name(), key_pair.public())),
+                pattern,
+            )?;
+            if let Some(key_pair) = config.keys.iter().find(|key_pair| key_pair.name() == name) {
+                if private_config.keys.iter().all(|added| added.public() != key_pair.public()) {
+                    private_config.keys.push(key_pair.clone());
+                }
+            }
+        }
+        // Config without path is never saved, so keys added by command stay in memory
+        let mut private_agent = Agent::new(private_config,
+                                           Box::new(platform::ask_confirmation));
+        if !opts.is_present("in-memory") {
+            private_agent = private_agent
+                .with_audit_log(AuditLog::new(platform::config_dir().join("audit.log")));
+        }
+
+        let socket_dir = create_temp_dir()?;
+        let _socket_dir = Finally::new(|| {
+            let _ = remove_dir_all(&socket_dir);
+        });
+        let socket_path = socket_dir.join("agent.sock");
+        let listener = platform::bind_socket(&socket_path)?;
+
+        let mut command = opts.values_of("command").unwrap();
+        let mut child = Command::new(command.next().unwrap())
+            .args(command)
+            .env("SSH_AUTH_SOCK", &socket_path)
+            .env_remove("SSH_AGENT_PID")
+            .spawn()
+            .wrap_err("start command")?;
+
+        let service = Service::new(Arc::new(private_agent), ConnectionLimits::default());
+        let runtime = service.runtime()?;
+        let run = {
+            let _guard = runtime.enter();
+            let listener = UnixSocketListener::from_std(listener)?;
+            let service = service.clone();
+            runtime.spawn(async move {
+                if let Err(err) = service.run(listener).await {
+                    error!("{}", err.wrap("serve private agent"));
+                }
+            })
+        };
+        let pid = child.id() as libc::pid_t;
+        runtime.spawn(async move {
+            if let Err(err) = forward_signals(pid).await {
+                error!("{}", err.wrap("forward signals"));
+            }
+        });
+        info!("private agent listening on {} pid={}", socket_path.display(), pid);
+
+        let status = child.wait().wrap_err("wait for command")?;
+        service.shutdown();
+        let _ = runtime.block_on(run);
+        runtime.shutdown_background();
+
+        // Same as shell, 128 + signal number if command was killed
+        Ok(status.code().or_else(|| status.signal().map(|signal| 128 + signal)).unwrap_or(1))
+    }
+
+    pub fn exec(agent: &Agent, opts: &ArgMatches) -> Result<()> {
+        let code = run(agent, opts)?;
+        std::process::exit(code)
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::Write;
+
+use clap::ArgMatches;
+use wrapperrs::{Error, Result, ResultExt};
+
+use ssh_keys::agent::Agent;
+use ssh_keys::client::{AgentClient, identities};
+use ssh_keys::key::{FingerprintHash, KeyPair, PrivateKey};
+
+const RSA_MIN_BITS: u32 = 2048;
+const RSA_DEFAULT_BITS: u32 = 3072;
+const ECDSA_DEFAULT_BITS: u32 = 256;
+
+pub fn generate(agent: &Agent, opts: &ArgMatches) -> Result<()> {
+    let name = opts.value_of("name").unwrap();
+    if identities(agent)?.iter().any(|identity| identity.comment == name) {
+        return Err(Error::new("key with this name already exists").into());
+    }
+
+    let bits = opts
+        .value_of("bits")
+        .map(|bits| bits.parse::<u32>())
+        .transpose()
+        .wrap_err("invalid bits")?;
+
+    let private = match opts.value_of("type").unwrap() {
+        "rsa" => {
+            let bits = bits.unwrap_or(RSA_DEFAULT_BITS);
+            if bits < RSA_MIN_BITS {
+                return Err(Error::new(
+                    &format!("rsa key size must be at least {} bits", RSA_MIN_BITS)).into());
+            }
+            PrivateKey::generate_rsa(bits)?
+        }
+        "ecdsa" => PrivateKey::generate_ecdsa(bits.unwrap_or(ECDSA_DEFAULT_BITS))?,
+        "ed25519" => {
+            if bits.is_some_and(|bits| bits != 256) {
+                return Err(Error::new("ed25519 keys have fixed size").into());
+            }
+            PrivateKey::generate_ed25519()?
+        }
+        key_type => return Err(Error::new(&format!("unknown key type: {}", key_type)).into()),
+    };
+
+    let key_pair = KeyPair::new(private, name.to_string())?;
+    let public_key = key_pair.public();
+    let public_line = format!("{} {}", public_key, key_pair.name());
+    let fingerprint = public_key.fingerprint();
+    let randomart = public_key.randomart(FingerprintHash::SHA256);
+
+    // Running agent would overwrite config with its own copy, so key has to go through it
+    match AgentClient::connect_running()? {
+        Some(mut client) => client.add_identity(key_pair).wrap_err("add key to agent")?,
+        None => agent.add_key(key_pair).wrap_err("add key")?,
+    }
+
+    if let Some(path) = opts.value_of("out") {
+        let mut file = File::create(path).wrap_err("create public key file")?;
+        file.write_all(format!("{}\n", public_line).as_bytes())?;
+    }
+
+    println!("{}", public_line);
+    println!("{}", fingerprint);
+    println!("{}", randomart);
+
+    Ok(())
+}
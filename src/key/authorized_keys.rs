@@ -0,0 +1,372 @@
+use std::fmt;
+use std::str::FromStr;
+
+use data_encoding::BASE64;
+use itertools::Itertools;
+use wrapperrs::{Error, Result, ResultExt};
+
+use crate::key::PublicKey;
+
+const KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Public key in OpenSSH single-line format: `<type> <base64> [comment]`
+#[derive(PartialEq)]
+pub struct PublicKeyLine {
+    pub key: PublicKey,
+    pub comment: Option<String>,
+}
+
+/// Single option from authorized_keys line, e.g. `restrict` or `from="10.0.0.0/8"`
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyOption {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(PartialEq)]
+pub struct AuthorizedKey {
+    pub options: Vec<KeyOption>,
+    pub key: PublicKey,
+    pub comment: Option<String>,
+}
+
+#[derive(PartialEq)]
+pub enum AuthorizedKeysLine {
+    Key(AuthorizedKey),
+    /// Blank lines, comments and keys this crate can't decode, kept verbatim
+    Other(String),
+}
+
+#[derive(Default, PartialEq)]
+pub struct AuthorizedKeys {
+    pub lines: Vec<AuthorizedKeysLine>,
+}
+
+fn split_token(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(pos) => (&s[..pos], s[pos..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim();
+    if s.is_empty() { None } else { Some(s.to_string()) }
+}
+
+fn parse_key(key_type: &str, data: &str) -> Result<PublicKey> {
+    let blob = BASE64.decode(data.as_bytes()).wrap_err("decode base64")?;
+    let key = PublicKey::decode(&blob)?;
+    if key.key_type() != key_type {
+        return Err(Error::new("key type doesn't match key data").into());
+    }
+    Ok(key)
+}
+
+/// Splits options prefix at the first unquoted whitespace
+fn parse_options(s: &str) -> Result<(Vec<KeyOption>, &str)> {
+    let mut options = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    let mut name = String::new();
+    let mut value: Option<String> = None;
+
+    let end = loop {
+        let (pos, c) = match chars.next() {
+            Some(next) => next,
+            None => break s.len(),
+        };
+        match c {
+            '"' => {
+                let value = value.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        // Same as sshd, only quotes are escaped and other backslashes are kept
+                        Some((_, '\\')) if matches!(chars.peek(), Some((_, '"'))) => {
+                            value.push(chars.next().unwrap().1);
+                        }
+                        Some((_, '"')) => break,
+                        Some((_, c)) => value.push(c),
+                        None => return Err(Error::new("unterminated quoted option").into()),
+                    }
+                }
+            }
+            '=' if value.is_none() => value = Some(String::new()),
+            ',' => {
+                options.push(KeyOption { name, value: value.take() });
+                name = String::new();
+            }
+            c if c.is_whitespace() => break pos,
+            c => match &mut value {
+                Some(value) => value.push(c),
+                None => name.push(c),
+            },
+        }
+    };
+
+    if name.is_empty() {
+        return Err(Error::new("empty option").into());
+    }
+    options.push(KeyOption { name, value });
+    if options.iter().any(|option| option.name.is_empty()) {
+        return Err(Error::new("empty option").into());
+    }
+    Ok((options, &s[end..]))
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.key_type(), BASE64.encode(&self.encode()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(s.parse::<PublicKeyLine>()?.key)
+    }
+}
+
+impl fmt::Display for PublicKeyLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.key)?;
+        if let Some(comment) = &self.comment {
+            write!(f, " {}", comment)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PublicKeyLine {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (key_type, rest) = split_token(s);
+        let (data, comment) = split_token(rest);
+        Ok(PublicKeyLine {
+            key: parse_key(key_type, data).wrap_err("parse public key")?,
+            comment: non_empty(comment),
+        })
+    }
+}
+
+impl KeyOption {
+    pub fn flag(name: &str) -> KeyOption {
+        KeyOption { name: name.to_string(), value: None }
+    }
+
+    pub fn with_value(name: &str, value: &str) -> KeyOption {
+        KeyOption { name: name.to_string(), value: Some(value.to_string()) }
+    }
+}
+
+/// Values are always quoted, sshd doesn't accept unquoted values for most options
+impl fmt::Display for KeyOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(value) = &self.value {
+            write!(f, "=\"{}\"", value.replace('"', "\\\""))?;
+        }
+        Ok(())
+    }
+}
+
+impl AuthorizedKey {
+    pub fn new(key: PublicKey, comment: Option<String>) -> AuthorizedKey {
+        AuthorizedKey { options: Vec::new(), key, comment }
+    }
+
+    pub fn option(&self, name: &str) -> Option<&KeyOption> {
+        self.options
+            .iter()
+            .find(|option| option.name.eq_ignore_ascii_case(name))
+    }
+
+    fn option_value(&self, name: &str) -> Option<&str> {
+        self.option(name).and_then(|option| option.value.as_deref())
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        self.option("restrict").is_some()
+    }
+
+    pub fn from(&self) -> Option<&str> {
+        self.option_value("from")
+    }
+
+    pub fn command(&self) -> Option<&str> {
+        self.option_value("command")
+    }
+
+    pub fn expiry_time(&self) -> Option<&str> {
+        self.option_value("expiry-time")
+    }
+}
+
+impl fmt::Display for AuthorizedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.options.is_empty() {
+            write!(f, "{} ", self.options.iter().join(","))?;
+        }
+        write!(f, "{}", self.key)?;
+        if let Some(comment) = &self.comment {
+            write!(f, " {}", comment)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AuthorizedKey {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let (first, _) = split_token(s);
+        let (options, rest) = if KEY_TYPES.contains(&first) {
+            (Vec::new(), s)
+        } else {
+            parse_options(s).wrap_err("parse options")?
+        };
+
+        let line = rest.parse::<PublicKeyLine>()?;
+        Ok(AuthorizedKey {
+            options,
+            key: line.key,
+            comment: line.comment,
+        })
+    }
+}
+
+impl fmt::Display for AuthorizedKeysLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthorizedKeysLine::Key(key) => write!(f, "{}", key),
+            AuthorizedKeysLine::Other(line) => write!(f, "{}", line),
+        }
+    }
+}
+
+impl AuthorizedKeys {
+    pub fn new() -> AuthorizedKeys {
+        AuthorizedKeys { lines: Vec::new() }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item=&AuthorizedKey> {
+        self.lines.iter().filter_map(|line| match line {
+            AuthorizedKeysLine::Key(key) => Some(key),
+            AuthorizedKeysLine::Other(_) => None,
+        })
+    }
+
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.keys().any(|authorized_key| &authorized_key.key == key)
+    }
+
+    pub fn add(&mut self, key: AuthorizedKey) {
+        self.lines.push(AuthorizedKeysLine::Key(key));
+    }
+}
+
+impl fmt::Display for AuthorizedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AuthorizedKeys {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let lines = s
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    return AuthorizedKeysLine::Other(line.to_string());
+                }
+                match trimmed.parse::<AuthorizedKey>() {
+                    Ok(key) => AuthorizedKeysLine::Key(key),
+                    Err(_) => AuthorizedKeysLine::Other(line.to_string()),
+                }
+            })
+            .collect();
+        Ok(AuthorizedKeys { lines })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = concat!("ssh-ed25519 ",
+                              "AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f");
+
+    fn round_trip(line: &str) -> String {
+        line.parse::<AuthorizedKey>().unwrap().to_string()
+    }
+
+    #[test]
+    fn display_keeps_line() {
+        for line in &[
+            KEY.to_string(),
+            format!("{} user@host", KEY),
+            format!("restrict,pty {}", KEY),
+            format!("from=\"10.0.0.0/8,192.168.0.0/16\",no-agent-forwarding {} c", KEY),
+            format!("command=\"echo \\\"hi\\\" \\\\ bye\" {}", KEY),
+            format!("expiry-time=\"20301231\",permitopen=\"host:22\" {}", KEY),
+            format!("environment=\"\" {}", KEY),
+        ] {
+            assert_eq!(&round_trip(line), line);
+        }
+    }
+
+    #[test]
+    fn escaped_values() {
+        let line = format!("command=\"echo \\\"a b\\\" c:\\\\d \\n\" {}", KEY);
+        let key = line.parse::<AuthorizedKey>().unwrap();
+        assert_eq!(key.command(), Some("echo \"a b\" c:\\\\d \\n"));
+    }
+
+    #[test]
+    fn quotes_values() {
+        let line = format!("expiry-time=20301231 {}", KEY);
+        assert_eq!(round_trip(&line), format!("expiry-time=\"20301231\" {}", KEY));
+
+        for value in &["a b", "a,b", "a\"b", "a\\b", "a\\\\b", ""] {
+            let option = KeyOption::with_value("command", value);
+            let key = AuthorizedKey { options: vec![option], ..KEY.parse().unwrap() };
+            let parsed = key.to_string().parse::<AuthorizedKey>().unwrap();
+            assert_eq!(parsed.command(), Some(*value));
+        }
+        let option = KeyOption::with_value("from", "10.0.0.1");
+        assert_eq!(option.to_string(), "from=\"10.0.0.1\"");
+    }
+
+    #[test]
+    fn authorized_keys_keep_other_lines() {
+        let file = format!("# comment\n\n{}\nnot a key\n", KEY);
+        let keys = file.parse::<AuthorizedKeys>().unwrap();
+        assert_eq!(keys.keys().count(), 1);
+        assert_eq!(keys.to_string(), file);
+    }
+
+    #[test]
+    fn invalid_options() {
+        for line in &[format!("command=\"unterminated {}", KEY), format!(",restrict {}", KEY)] {
+            assert!(line.parse::<AuthorizedKey>().is_err());
+        }
+    }
+}
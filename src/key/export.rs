@@ -0,0 +1,298 @@
+use std::str::FromStr;
+
+use data_encoding::BASE64;
+use openssl::bn::{BigNumContext, BigNumRef};
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::pkey::{Id, PKey, Public};
+use openssl::rsa::Rsa;
+use wrapperrs::{Error, Result, ResultExt};
+
+use crate::key::{curve_name, curve_nid, ED25519_KEY_LEN, PublicKey, PublicKeyLine};
+
+// SubjectPublicKeyInfo header for a raw 32 byte Ed25519 public key
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+const RFC4716_BEGIN: &str = "---- BEGIN SSH2 PUBLIC KEY ----";
+const RFC4716_END: &str = "---- END SSH2 PUBLIC KEY ----";
+const RFC4716_LINE_LEN: usize = 70;
+/// Header lines including continuation backslash, RFC 4716 section 3.3
+const RFC4716_HEADER_LINE_LEN: usize = 72;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PublicKeyFormat {
+    /// `<type> <base64> [comment]`, as in authorized_keys
+    OpenSSH,
+    /// `---- BEGIN SSH2 PUBLIC KEY ----`
+    RFC4716,
+    /// PEM encoded SubjectPublicKeyInfo, `-----BEGIN PUBLIC KEY-----`
+    PKCS8,
+    /// PKCS#1 `-----BEGIN RSA PUBLIC KEY-----` for RSA keys, SubjectPublicKeyInfo otherwise
+    PEM,
+}
+
+impl FromStr for PublicKeyFormat {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "openssh" => Ok(PublicKeyFormat::OpenSSH),
+            "rfc4716" | "ssh2" => Ok(PublicKeyFormat::RFC4716),
+            "pkcs8" => Ok(PublicKeyFormat::PKCS8),
+            "pem" => Ok(PublicKeyFormat::PEM),
+            _ => Err(Error::new(&format!("unknown public key format: {}", s)).into()),
+        }
+    }
+}
+
+/// Long header is continued on following lines, each but the last ending with `\`
+fn push_header(out: &mut String, header: &str) {
+    let mut rest = header;
+    while rest.len() > RFC4716_HEADER_LINE_LEN {
+        let mut split = RFC4716_HEADER_LINE_LEN - 1;
+        while !rest.is_char_boundary(split) {
+            split -= 1;
+        }
+        out.push_str(&rest[..split]);
+        out.push_str("\\\n");
+        rest = &rest[split..];
+    }
+    out.push_str(rest);
+    out.push('\n');
+}
+
+fn export_rfc4716(key: &PublicKey, comment: Option<&str>) -> String {
+    let mut out = format!("{}\n", RFC4716_BEGIN);
+    if let Some(comment) = comment {
+        push_header(&mut out, &format!("Comment: \"{}\"", comment.replace('"', "\\\"")));
+    }
+    let data = BASE64.encode(&key.encode());
+    for chunk in data.as_bytes().chunks(RFC4716_LINE_LEN) {
+        out.push_str(std::str::from_utf8(chunk).unwrap());
+        out.push('\n');
+    }
+    out.push_str(RFC4716_END);
+    out.push('\n');
+    out
+}
+
+fn import_rfc4716(s: &str) -> Result<(PublicKey, Option<String>)> {
+    let mut lines = s.lines().skip_while(|line| line.trim().is_empty());
+    if lines.next().map(str::trim) != Some(RFC4716_BEGIN) {
+        return Err(Error::new("missing begin marker").into());
+    }
+
+    let mut comment = None;
+    let mut data = String::new();
+    let mut header: Option<String> = None;
+    let mut ended = false;
+    for raw_line in lines {
+        let line = raw_line.trim();
+        if line == RFC4716_END {
+            ended = true;
+            break;
+        }
+
+        // Header lines end with '\' when continued on the next line. Continuation isn't
+        // trimmed at the start, as header may have been split at a space
+        if let Some(mut value) = header.take() {
+            let line = raw_line.trim_end();
+            value.push_str(line.strip_suffix('\\').unwrap_or(line));
+            if line.ends_with('\\') {
+                header = Some(value);
+            } else if let Some((tag, value)) = split_header(&value) {
+                if tag.eq_ignore_ascii_case("comment") {
+                    comment = Some(value);
+                }
+            }
+        } else if line.contains(':') && data.is_empty() {
+            if let Some(line) = line.strip_suffix('\\') {
+                header = Some(line.to_string());
+            } else if let Some((tag, value)) = split_header(line) {
+                if tag.eq_ignore_ascii_case("comment") {
+                    comment = Some(value);
+                }
+            }
+        } else {
+            data.push_str(line);
+        }
+    }
+    if !ended {
+        return Err(Error::new("missing end marker").into());
+    }
+
+    let blob = BASE64.decode(data.as_bytes()).wrap_err("decode base64")?;
+    Ok((PublicKey::decode(&blob)?, comment))
+}
+
+fn split_header(line: &str) -> Option<(&str, String)> {
+    let pos = line.find(':')?;
+    let value = line[pos + 1..].trim();
+    let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        value[1..value.len() - 1].replace("\\\"", "\"")
+    } else {
+        value.to_string()
+    };
+    Some((line[..pos].trim(), value))
+}
+
+impl PublicKey {
+    /// Converts key to openssl representation, used for PEM export
+    pub fn to_pkey(&self) -> Result<PKey<Public>> {
+        use PublicKey::*;
+
+        Ok(match self {
+            RSA { e, n } => {
+                let rsa = Rsa::from_public_components(BigNumRef::to_owned(n)?,
+                                                      BigNumRef::to_owned(e)?)?;
+                PKey::from_rsa(rsa)?
+            }
+            ECDSA { curve, q } => {
+                let group = EcGroup::from_curve_name(curve_nid(curve)?)?;
+                let mut ctx = BigNumContext::new()?;
+                let point = EcPoint::from_bytes(&group, q, &mut ctx).wrap_err("read point")?;
+                PKey::from_ec_key(EcKey::from_public_key(&group, &point)?)?
+            }
+            Ed25519(key) => {
+                let mut der = ED25519_SPKI_PREFIX.to_vec();
+                der.extend_from_slice(key);
+                PKey::public_key_from_der(&der)?
+            }
+        })
+    }
+
+    pub fn from_pkey(pkey: &PKey<Public>) -> Result<PublicKey> {
+        match pkey.id() {
+            Id::RSA => {
+                let rsa = pkey.rsa()?;
+                Ok(PublicKey::RSA {
+                    e: rsa.e().to_owned()?,
+                    n: rsa.n().to_owned()?,
+                })
+            }
+            Id::EC => {
+                let ec = pkey.ec_key()?;
+                let group = ec.group();
+                let nid = group.curve_name().ok_or(Error::new("unnamed curve"))?;
+                let mut ctx = BigNumContext::new()?;
+                Ok(PublicKey::ECDSA {
+                    curve: curve_name(nid)?.to_string(),
+                    q: ec.public_key().to_bytes(group, PointConversionForm::UNCOMPRESSED, &mut ctx)?,
+                })
+            }
+            Id::ED25519 => {
+                let der = pkey.public_key_to_der()?;
+                Ok(PublicKey::Ed25519(der[der.len() - ED25519_KEY_LEN..].to_vec()))
+            }
+            _ => Err(Error::new("unsupported key type").into()),
+        }
+    }
+
+    pub fn export(&self, format: PublicKeyFormat, comment: Option<&str>) -> Result<String> {
+        Ok(match format {
+            PublicKeyFormat::OpenSSH => format!("{}\n", PublicKeyLine {
+                key: self.clone(),
+                comment: comment.map(str::to_string),
+            }),
+            PublicKeyFormat::RFC4716 => export_rfc4716(self, comment),
+            PublicKeyFormat::PEM if self.key_type() == "ssh-rsa" => {
+                let rsa = self.to_pkey()?.rsa()?;
+                String::from_utf8(rsa.public_key_to_pem_pkcs1()?)?
+            }
+            PublicKeyFormat::PKCS8 | PublicKeyFormat::PEM => {
+                String::from_utf8(self.to_pkey()?.public_key_to_pem()?)?
+            }
+        })
+    }
+
+    /// Reads public key in any of `PublicKeyFormat`s, detecting the format from content
+    pub fn import(s: &str) -> Result<(PublicKey, Option<String>)> {
+        let trimmed = s.trim_start();
+        if trimmed.starts_with(RFC4716_BEGIN) {
+            Ok(import_rfc4716(trimmed).wrap_err("parse rfc4716 key")?)
+        } else if trimmed.starts_with("-----BEGIN RSA PUBLIC KEY-----") {
+            let rsa = Rsa::public_key_from_pem_pkcs1(trimmed.as_bytes())
+                .wrap_err("parse pkcs1 key")?;
+            Ok((PublicKey::from_pkey(&PKey::from_rsa(rsa)?)?, None))
+        } else if trimmed.starts_with("-----BEGIN PUBLIC KEY-----") {
+            let pkey = PKey::public_key_from_pem(trimmed.as_bytes())
+                .wrap_err("parse pkcs8 key")?;
+            Ok((PublicKey::from_pkey(&pkey)?, None))
+        } else {
+            let line = trimmed.lines().next().unwrap_or("").parse::<PublicKeyLine>()?;
+            Ok((line.key, line.comment))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::{KeyPair, PrivateKey};
+
+    use super::*;
+
+    const FORMATS: [PublicKeyFormat; 4] = [PublicKeyFormat::OpenSSH, PublicKeyFormat::RFC4716,
+                                           PublicKeyFormat::PKCS8, PublicKeyFormat::PEM];
+
+    fn keys() -> Vec<PublicKey> {
+        vec![
+            PrivateKey::generate_rsa(2048).unwrap(),
+            PrivateKey::generate_ecdsa(256).unwrap(),
+            PrivateKey::generate_ecdsa(384).unwrap(),
+            PrivateKey::generate_ecdsa(521).unwrap(),
+            PrivateKey::generate_ed25519().unwrap(),
+        ].into_iter()
+            .map(|key| KeyPair::new(key, String::new()).unwrap().public().clone())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for key in keys() {
+            for &format in &FORMATS {
+                let exported = key.export(format, Some("user@host")).unwrap();
+                let (imported, comment) = PublicKey::import(&exported).unwrap();
+                assert!(imported == key, "{:?} {}", format, key.key_type());
+                let keeps_comment = [PublicKeyFormat::OpenSSH, PublicKeyFormat::RFC4716];
+                let expected = Some("user@host").filter(|_| keeps_comment.contains(&format));
+                assert_eq!(comment.as_deref(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn rfc4716_long_comment() {
+        let key = keys().pop().unwrap();
+        let comment = format!("{} \"quoted\" ends with \\", "long comment ".repeat(20));
+        let exported = key.export(PublicKeyFormat::RFC4716, Some(&comment)).unwrap();
+        assert!(exported.lines().all(|line| line.len() <= RFC4716_HEADER_LINE_LEN));
+        assert!(exported.lines().nth(1).unwrap().ends_with('\\'));
+        let (imported, imported_comment) = PublicKey::import(&exported).unwrap();
+        assert!(imported == key);
+        assert_eq!(imported_comment, Some(comment));
+
+        // Multi-byte chars aren't split
+        let comment = "ключ ".repeat(20);
+        let exported = key.export(PublicKeyFormat::RFC4716, Some(&comment)).unwrap();
+        assert!(exported.lines().all(|line| line.len() <= RFC4716_HEADER_LINE_LEN));
+        assert_eq!(PublicKey::import(&exported).unwrap().1, Some(comment));
+    }
+
+    #[test]
+    fn rfc4716_headers() {
+        let key = concat!(
+            "---- BEGIN SSH2 PUBLIC KEY ----\n",
+            "Subject: user\n",
+            "Comment: \"split \\\n",
+            "across lines\"\n",
+            "x-private: \\\n",
+            "ignored\n",
+            "AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f\n",
+            "---- END SSH2 PUBLIC KEY ----\n",
+        );
+        let (imported, comment) = PublicKey::import(key).unwrap();
+        assert_eq!(imported.fingerprint(), "SHA256:ZkAslGjFiUHdGf/WUL8rQvkib4PTvQatUV0OUQSncCA");
+        assert_eq!(comment.as_deref(), Some("split across lines"));
+    }
+}
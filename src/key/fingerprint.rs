@@ -0,0 +1,178 @@
+use std::cmp::min;
+
+use data_encoding::BASE64_NOPAD;
+use itertools::Itertools;
+use openssl::hash::{hash, MessageDigest};
+
+use crate::key::PublicKey;
+
+const RANDOMART_WIDTH: usize = 17;
+const RANDOMART_HEIGHT: usize = 9;
+const RANDOMART_SYMBOLS: &[u8] = b" .o+=*BOX@%&#/^SE";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FingerprintHash {
+    SHA256,
+    MD5,
+}
+
+impl FingerprintHash {
+    fn digest(&self) -> MessageDigest {
+        match self {
+            FingerprintHash::SHA256 => MessageDigest::sha256(),
+            FingerprintHash::MD5 => MessageDigest::md5(),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            FingerprintHash::SHA256 => "SHA256",
+            FingerprintHash::MD5 => "MD5",
+        }
+    }
+}
+
+impl PublicKey {
+    fn fingerprint_raw(&self, hash_type: FingerprintHash) -> Vec<u8> {
+        hash(hash_type.digest(), &self.encode()).unwrap().to_vec()
+    }
+
+    /// Fingerprint formatted the same way as `ssh-keygen -l -E <hash>`
+    pub fn fingerprint_with(&self, hash_type: FingerprintHash) -> String {
+        let digest = self.fingerprint_raw(hash_type);
+        let encoded = match hash_type {
+            FingerprintHash::SHA256 => BASE64_NOPAD.encode(&digest),
+            FingerprintHash::MD5 => digest.iter()
+                .map(|b| format!("{:02x}", b))
+                .join(":"),
+        };
+        format!("{}:{}", hash_type.name(), encoded)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        self.fingerprint_with(FingerprintHash::SHA256)
+    }
+
+    /// "Drunken bishop" visualisation of the fingerprint, compatible with `ssh-keygen -lv`
+    pub fn randomart(&self, hash_type: FingerprintHash) -> String {
+        let max_value = RANDOMART_SYMBOLS.len() - 1;
+        let mut field = [[0usize; RANDOMART_HEIGHT]; RANDOMART_WIDTH];
+        let (mut x, mut y) = (RANDOMART_WIDTH / 2, RANDOMART_HEIGHT / 2);
+
+        for mut input in self.fingerprint_raw(hash_type) {
+            for _ in 0..4 {
+                x = if input & 0x1 > 0 { min(x + 1, RANDOMART_WIDTH - 1) } else { x.saturating_sub(1) };
+                y = if input & 0x2 > 0 { min(y + 1, RANDOMART_HEIGHT - 1) } else { y.saturating_sub(1) };
+                if field[x][y] < max_value - 2 {
+                    field[x][y] += 1;
+                }
+                input >>= 2;
+            }
+        }
+        field[RANDOMART_WIDTH / 2][RANDOMART_HEIGHT / 2] = max_value - 1;
+        field[x][y] = max_value;
+
+        fn border(title: &str) -> String {
+            if title.len() > RANDOMART_WIDTH {
+                return format!("+{}+", "-".repeat(RANDOMART_WIDTH));
+            }
+            let left = (RANDOMART_WIDTH - title.len()) / 2;
+            let right = RANDOMART_WIDTH - title.len() - left;
+            format!("+{}{}{}+", "-".repeat(left), title, "-".repeat(right))
+        }
+
+        let mut title = format!("[{} {}]", self.type_name(), self.bits());
+        if title.len() >= RANDOMART_WIDTH {
+            title = format!("[{}]", self.type_name());
+        }
+
+        let mut art = border(&title);
+        art.push('\n');
+        for y in 0..RANDOMART_HEIGHT {
+            art.push('|');
+            for x in 0..RANDOMART_WIDTH {
+                art.push(RANDOMART_SYMBOLS[min(field[x][y], max_value)] as char);
+            }
+            art.push_str("|\n");
+        }
+        art.push_str(&border(&format!("[{}]", hash_type.name())));
+        art
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::PublicKeyLine;
+
+    use super::*;
+
+    // Expected values are output of `ssh-keygen -lv [-E md5]` for the same keys
+    const ED25519: &str = concat!(
+        "ssh-ed25519 ",
+        "AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f",
+    );
+    const ECDSA: &str = concat!(
+        "ecdsa-sha2-nistp256 ",
+        "AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBNwIjHVm/qrhgNno",
+        "0eFuwAHgg3tOhd5eBiuCN58ZLjapkA0d8TWbgqHZXC/Z/f4e3chdYjY0Zk3+Z0xUy634hcc=",
+    );
+
+    fn key(line: &str) -> PublicKey {
+        line.parse::<PublicKeyLine>().unwrap().key
+    }
+
+    #[test]
+    fn fingerprints() {
+        let ed25519 = key(ED25519);
+        assert_eq!(ed25519.fingerprint(), "SHA256:ZkAslGjFiUHdGf/WUL8rQvkib4PTvQatUV0OUQSncCA");
+        assert_eq!(ed25519.fingerprint_with(FingerprintHash::MD5),
+                   "MD5:0f:a2:0a:d7:38:3e:65:45:08:6b:63:84:1c:ff:dc:ba");
+        let ecdsa = key(ECDSA);
+        assert_eq!(ecdsa.fingerprint(), "SHA256:B/sToK2hIebbRXoFESHDpuMNu7gar4mOkhA670gqRDI");
+        assert_eq!(ecdsa.fingerprint_with(FingerprintHash::MD5),
+                   "MD5:c2:7c:6f:0a:56:f5:a1:df:f1:a2:63:f8:aa:8e:df:59");
+    }
+
+    #[test]
+    fn randomart() {
+        assert_eq!(key(ED25519).randomart(FingerprintHash::SHA256), concat!(
+            "+--[ED25519 256]--+\n",
+            "| .oOo=oo  E +.+=+|\n",
+            "|  + *.+.   o +.o.|\n",
+            "| .   .. . .  .o+ |\n",
+            "|       . . +. ...|\n",
+            "|        S =o. .  |\n",
+            "|       o oo..  . |\n",
+            "|        .oo=o .  |\n",
+            "|        oo=oo.   |\n",
+            "|         o.o..   |\n",
+            "+----[SHA256]-----+",
+        ));
+        assert_eq!(key(ED25519).randomart(FingerprintHash::MD5), concat!(
+            "+--[ED25519 256]--+\n",
+            "|.o+. .           |\n",
+            "|.o... .          |\n",
+            "|  =. .           |\n",
+            "| o .o o          |\n",
+            "|     +..S        |\n",
+            "|   oo... o       |\n",
+            "|. +oo.    .      |\n",
+            "| +.o  .          |\n",
+            "|  o. E           |\n",
+            "+------[MD5]------+",
+        ));
+        assert_eq!(key(ECDSA).randomart(FingerprintHash::SHA256), concat!(
+            "+---[ECDSA 256]---+\n",
+            "|   .o +o         |\n",
+            "|    oo .         |\n",
+            "|   o  . o        |\n",
+            "|E.+    + +       |\n",
+            "|++o=. + S o      |\n",
+            "|++o..= + o .     |\n",
+            "|+*..o +   o      |\n",
+            "|X=oo o     .     |\n",
+            "|#=+ .            |\n",
+            "+----[SHA256]-----+",
+        ));
+    }
+}
@@ -1,29 +1,124 @@
-use std::io::{Cursor, Read};
+use std::fmt;
+use std::io::{Cursor, Read, Write};
+use std::sync::Arc;
 
 use openssl::{bn::BigNum, hash::MessageDigest, pkey::PKey, pkey::Private, rsa::Rsa, sign::Signer};
+use openssl::bn::{BigNumContext, BigNumRef};
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use openssl::pkey::PKeyRef;
 use wrapperrs::{Error, Result, ResultExt};
 
+pub use authorized_keys::*;
+pub use export::*;
+pub use fingerprint::*;
 pub use ser::*;
 
-use crate::agent::wire::{ReadExt, WriteExt};
+use crate::agent::wire::{ensure_consumed, ReadExt, WriteExt};
+use crate::logging::Secret;
 
+mod authorized_keys;
+mod export;
+mod fingerprint;
+mod import;
 mod ser;
 
+// PKCS#8 PrivateKeyInfo header for a raw 32 byte Ed25519 seed
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+const ED25519_KEY_LEN: usize = 32;
+
 pub enum PrivateKey {
     RSA(Rsa<Private>),
+    ECDSA(EcKey<Private>),
+    Ed25519(PKey<Private>),
 }
 
 pub enum PublicKey {
     RSA { e: BigNum, n: BigNum },
+    ECDSA { curve: String, q: Vec<u8> },
+    Ed25519(Vec<u8>),
 }
 
+/// Cheap to clone, private key material is shared
+#[derive(Clone)]
 pub struct KeyPair {
-    private: PrivateKey,
+    private: Arc<PrivateKey>,
+    /// Prepared once for signing, instead of converting key on every request
+    pkey: PKey<Private>,
     public: PublicKey,
     name: String,
+    /// Kept only in memory, never written to config
+    ephemeral: bool,
+}
+
+fn curve_nid(curve: &str) -> Result<Nid> {
+    match curve {
+        "nistp256" => Ok(Nid::X9_62_PRIME256V1),
+        "nistp384" => Ok(Nid::SECP384R1),
+        "nistp521" => Ok(Nid::SECP521R1),
+        _ => Err(Error::new(&format!("unknown curve: {}", curve)).into()),
+    }
+}
+
+fn curve_name(nid: Nid) -> Result<&'static str> {
+    match nid {
+        Nid::X9_62_PRIME256V1 => Ok("nistp256"),
+        Nid::SECP384R1 => Ok("nistp384"),
+        Nid::SECP521R1 => Ok("nistp521"),
+        _ => Err(Error::new("unsupported curve").into()),
+    }
+}
+
+fn curve_digest(curve: &str) -> MessageDigest {
+    match curve {
+        "nistp256" => MessageDigest::sha256(),
+        "nistp384" => MessageDigest::sha384(),
+        _ => MessageDigest::sha512(),
+    }
+}
+
+fn ed25519_from_seed(seed: &[u8]) -> Result<PKey<Private>> {
+    if seed.len() != ED25519_KEY_LEN {
+        return Err(Error::new("invalid ed25519 seed length").into());
+    }
+    let mut der = ED25519_PKCS8_PREFIX.to_vec();
+    der.extend_from_slice(seed);
+    Ok(PKey::private_key_from_der(&der).wrap_err("create key")?)
+}
+
+fn ed25519_public(key: &PKeyRef<Private>) -> Vec<u8> {
+    let der = key.public_key_to_der().unwrap();
+    der[der.len() - ED25519_KEY_LEN..].to_vec()
+}
+
+fn ed25519_seed(key: &PKeyRef<Private>) -> Vec<u8> {
+    let der = key.private_key_to_der().unwrap();
+    der[der.len() - ED25519_KEY_LEN..].to_vec()
 }
 
 impl PrivateKey {
+    pub fn generate_rsa(bits: u32) -> Result<PrivateKey> {
+        Ok(PrivateKey::RSA(Rsa::generate(bits).wrap_err("generate rsa key")?))
+    }
+
+    pub fn generate_ecdsa(bits: u32) -> Result<PrivateKey> {
+        let curve = match bits {
+            256 => "nistp256",
+            384 => "nistp384",
+            521 => "nistp521",
+            _ => return Err(Error::new("ecdsa key size must be 256, 384 or 521").into()),
+        };
+        let group = EcGroup::from_curve_name(curve_nid(curve)?)?;
+        Ok(PrivateKey::ECDSA(EcKey::generate(&group).wrap_err("generate ecdsa key")?))
+    }
+
+    pub fn generate_ed25519() -> Result<PrivateKey> {
+        Ok(PrivateKey::Ed25519(PKey::generate_ed25519().wrap_err("generate ed25519 key")?))
+    }
+
     fn from_wire<R: Read>(r: &mut R) -> Result<PrivateKey> {
         let key_type = r.read_string_utf8()?;
         match key_type.as_str() {
@@ -35,51 +130,166 @@ impl PrivateKey {
                 let p = r.read_mpint()?;
                 let q = r.read_mpint()?;
 
-                let one = BigNum::from_u32(1).unwrap();
-                let dp = &d % &(&p - &one);
-                let dq = &d % &(&q - &one);
+                // Checked, as `%` panics on zero divisor, e.g. when p is 1
+                let one = BigNum::from_u32(1)?;
+                let mut ctx = BigNumContext::new()?;
+                let mut dp = BigNum::new()?;
+                dp.checked_rem(&d, &(&p - &one), &mut ctx).wrap_err("calculate dp")?;
+                let mut dq = BigNum::new()?;
+                dq.checked_rem(&d, &(&q - &one), &mut ctx).wrap_err("calculate dq")?;
 
                 Ok(PrivateKey::RSA(
                     Rsa::from_private_components(n, e, d, p, q, dp, dq, iqmp)
                         .wrap_err("create key")?,
                 ))
             }
+            "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => {
+                let curve = r.read_string_utf8()?;
+                if key_type[11..] != curve[..] {
+                    return Err(Error::new("curve doesn't match key type").into());
+                }
+                let q = r.read_string()?;
+                let d = r.read_mpint()?;
+
+                let group = EcGroup::from_curve_name(curve_nid(&curve)?)?;
+                let mut ctx = BigNumContext::new()?;
+                let point = EcPoint::from_bytes(&group, &q, &mut ctx).wrap_err("read point")?;
+                let key = EcKey::from_private_components(&group, &d, &point)
+                    .wrap_err("create key")?;
+                key.check_key().wrap_err("check key")?;
+                Ok(PrivateKey::ECDSA(key))
+            }
+            "ssh-ed25519" => {
+                let public = r.read_string()?;
+                let private = r.read_string()?;
+                if private.len() != 2 * ED25519_KEY_LEN {
+                    return Err(Error::new("invalid ed25519 key length").into());
+                }
+
+                let key = ed25519_from_seed(&private[..ED25519_KEY_LEN])?;
+                if ed25519_public(&key) != public {
+                    return Err(Error::new("public key doesn't match private key").into());
+                }
+                Ok(PrivateKey::Ed25519(key))
+            }
             _ => Err(Error::new(&format!("unknown key type: {}", key_type)).into()),
         }
     }
 
-    fn public(&self) -> PublicKey {
+    fn to_wire<W: Write>(&self, w: &mut W) -> Result<()> {
         use PrivateKey::*;
 
         match self {
+            RSA(key) => {
+                w.write_string("ssh-rsa")?;
+                w.write_mpint(key.n())?;
+                w.write_mpint(key.e())?;
+                w.write_mpint(key.d())?;
+                w.write_mpint(key.iqmp().ok_or(Error::new("missing iqmp"))?)?;
+                w.write_mpint(key.p().ok_or(Error::new("missing p"))?)?;
+                w.write_mpint(key.q().ok_or(Error::new("missing q"))?)?;
+            }
+            ECDSA(key) => {
+                let public = self.public()?;
+                w.write_string(public.key_type())?;
+                if let PublicKey::ECDSA { curve, q } = &public {
+                    w.write_string(curve)?;
+                    w.write_string(q)?;
+                }
+                w.write_mpint(key.private_key())?;
+            }
+            Ed25519(key) => {
+                let public = ed25519_public(key);
+                let mut private = ed25519_seed(key);
+                private.extend_from_slice(&public);
+                w.write_string("ssh-ed25519")?;
+                w.write_string(public)?;
+                w.write_string(private)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails for ECDSA keys on curves other than nistp256, nistp384 and nistp521
+    fn public(&self) -> Result<PublicKey> {
+        use PrivateKey::*;
+
+        Ok(match self {
             RSA(key) => PublicKey::RSA {
-                e: key.e().to_owned().unwrap(),
-                n: key.n().to_owned().unwrap(),
+                e: key.e().to_owned()?,
+                n: key.n().to_owned()?,
             },
-        }
+            ECDSA(key) => {
+                let group = key.group();
+                let mut ctx = BigNumContext::new()?;
+                PublicKey::ECDSA {
+                    curve: curve_name(group.curve_name().ok_or(Error::new("unnamed curve"))?)?
+                        .to_string(),
+                    q: key.public_key()
+                        .to_bytes(group, PointConversionForm::UNCOMPRESSED, &mut ctx)?,
+                }
+            }
+            Ed25519(key) => PublicKey::Ed25519(ed25519_public(key)),
+        })
     }
 
+    /// Converts key to openssl representation used for signing
+    pub fn to_pkey(&self) -> Result<PKey<Private>> {
+        use PrivateKey::*;
+
+        Ok(match self {
+            RSA(key) => PKey::from_rsa(key.clone()).wrap_err("create pkey")?,
+            ECDSA(key) => PKey::from_ec_key(key.clone()).wrap_err("create pkey")?,
+            Ed25519(key) => key.clone(),
+        })
+    }
+
+    /// Prepares key on every call, `KeyPair::sign` should be used for repeated signing
     pub fn sign(&self, msg: &[u8], flags: u32) -> Result<Vec<u8>> {
+        let pkey = self.to_pkey()?;
+        self.sign_with(&pkey, msg, flags)
+    }
+
+    /// Signs with `pkey` prepared from this key by `to_pkey`
+    fn sign_with(&self, pkey: &PKeyRef<Private>, msg: &[u8], flags: u32) -> Result<Vec<u8>> {
         use PrivateKey::*;
 
+        let mut sig = Vec::new();
         match self {
-            RSA(key) => {
-                let pkey = PKey::from_rsa(key.clone()).wrap_err("create pkey")?;
-
+            RSA(_) => {
                 let (digest, sig_type) = match 1 {
                     _ if flags & 0x4 > 0 => (MessageDigest::sha512(), "rsa-sha2-512"),
                     _ if flags & 0x2 > 0 => (MessageDigest::sha256(), "rsa-sha2-256"),
                     _ => (MessageDigest::sha1(), "ssh-rsa"),
                 };
 
-                let mut signer = Signer::new(digest, &pkey).wrap_err("create signer")?;
+                let mut signer = Signer::new(digest, pkey).wrap_err("create signer")?;
 
-                let mut sig = Vec::new();
                 sig.write_string(sig_type)?;
                 sig.write_string(signer.sign_oneshot_to_vec(msg)?)?;
-                Ok(sig)
+            }
+            ECDSA(key) => {
+                let curve = curve_name(key.group().curve_name().unwrap())?;
+                let mut signer = Signer::new(curve_digest(curve), pkey)
+                    .wrap_err("create signer")?;
+                let der = signer.sign_oneshot_to_vec(msg)?;
+                let ecdsa_sig = EcdsaSig::from_der(&der).wrap_err("decode signature")?;
+
+                let mut blob = Vec::new();
+                blob.write_mpint(ecdsa_sig.r())?;
+                blob.write_mpint(ecdsa_sig.s())?;
+
+                sig.write_string(format!("ecdsa-sha2-{}", curve))?;
+                sig.write_string(blob)?;
+            }
+            Ed25519(_) => {
+                let mut signer = Signer::new_without_digest(pkey).wrap_err("create signer")?;
+
+                sig.write_string("ssh-ed25519")?;
+                sig.write_string(signer.sign_oneshot_to_vec(msg)?)?;
             }
         }
+        Ok(sig)
     }
 }
 
@@ -88,31 +298,99 @@ impl PublicKey {
         use PublicKey::*;
         match self {
             RSA { .. } => "ssh-rsa",
+            ECDSA { curve, .. } => match curve.as_str() {
+                "nistp256" => "ecdsa-sha2-nistp256",
+                "nistp384" => "ecdsa-sha2-nistp384",
+                _ => "ecdsa-sha2-nistp521",
+            },
+            Ed25519(_) => "ssh-ed25519",
         }
     }
 
     pub fn encode(&self) -> Vec<u8> {
         use PublicKey::*;
 
+        let mut buf = Vec::new();
+        buf.write_string(self.key_type()).unwrap();
         match self {
             RSA { e, n } => {
-                let mut buf = Vec::new();
-                buf.write_string("ssh-rsa").unwrap();
                 buf.write_mpint(e).unwrap();
                 buf.write_mpint(n).unwrap();
-                buf
+            }
+            ECDSA { curve, q } => {
+                buf.write_string(curve).unwrap();
+                buf.write_string(q).unwrap();
+            }
+            Ed25519(key) => {
+                buf.write_string(key).unwrap();
             }
         }
+        buf
     }
 
     pub fn decode(buf: &[u8]) -> Result<PublicKey> {
         let mut cur = Cursor::new(buf);
-        match cur.read_string_utf8()?.as_str() {
-            "ssh-rsa" => Ok(PublicKey::RSA {
+        let key = match cur.read_string_utf8()?.as_str() {
+            "ssh-rsa" => PublicKey::RSA {
                 e: cur.read_mpint()?,
                 n: cur.read_mpint()?,
-            }),
-            key_type => Err(Error::new(&format!("unknown key type: {}", key_type)).into()),
+            },
+            key_type @ "ecdsa-sha2-nistp256"
+            | key_type @ "ecdsa-sha2-nistp384"
+            | key_type @ "ecdsa-sha2-nistp521" => {
+                let curve = cur.read_string_utf8()?;
+                if key_type[11..] != curve[..] {
+                    return Err(Error::new("curve doesn't match key type").into());
+                }
+                PublicKey::ECDSA { curve, q: cur.read_string()? }
+            }
+            "ssh-ed25519" => {
+                let key = cur.read_string()?;
+                if key.len() != ED25519_KEY_LEN {
+                    return Err(Error::new("invalid ed25519 key length").into());
+                }
+                PublicKey::Ed25519(key)
+            }
+            key_type => return Err(Error::new(&format!("unknown key type: {}", key_type)).into()),
+        };
+        ensure_consumed(&cur)?;
+        Ok(key)
+    }
+
+    pub fn type_name(&self) -> &str {
+        use PublicKey::*;
+        match self {
+            RSA { .. } => "RSA",
+            ECDSA { .. } => "ECDSA",
+            Ed25519(_) => "ED25519",
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        use PublicKey::*;
+        match self {
+            RSA { n, .. } => n.num_bits() as u32,
+            ECDSA { curve, .. } => match curve.as_str() {
+                "nistp256" => 256,
+                "nistp384" => 384,
+                _ => 521,
+            },
+            Ed25519(_) => 256,
+        }
+    }
+}
+
+impl Clone for PublicKey {
+    fn clone(&self) -> Self {
+        use PublicKey::*;
+
+        match self {
+            RSA { e, n } => RSA {
+                e: BigNumRef::to_owned(e).unwrap(),
+                n: BigNumRef::to_owned(n).unwrap(),
+            },
+            ECDSA { curve, q } => ECDSA { curve: curve.clone(), q: q.clone() },
+            Ed25519(key) => Ed25519(key.clone()),
         }
     }
 }
@@ -121,38 +399,70 @@ impl PartialEq<PublicKey> for PublicKey {
     fn eq(&self, other: &PublicKey) -> bool {
         use PublicKey::*;
 
-        match self {
-            RSA { n, e } => {
-                let RSA { e: e1, n: n1 } = other;
-                n == n1 && e == e1
-            }
+        match (self, other) {
+            (RSA { n, e }, RSA { e: e1, n: n1 }) => n == n1 && e == e1,
+            (ECDSA { curve, q }, ECDSA { curve: curve1, q: q1 }) => curve == curve1 && q == q1,
+            (Ed25519(key), Ed25519(key1)) => key == key1,
+            _ => false,
         }
     }
 }
 
+/// Key material is never printed, only key type
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PrivateKey::RSA(_) => "RSA",
+            PrivateKey::ECDSA(_) => "ECDSA",
+            PrivateKey::Ed25519(_) => "Ed25519",
+        };
+        f.debug_tuple(name).field(&Secret(())).finish()
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("name", &self.name)
+            .field("fingerprint", &self.public.fingerprint())
+            .field("ephemeral", &self.ephemeral)
+            .field("private", &self.private)
+            .finish()
+    }
+}
+
 impl KeyPair {
-    pub fn new(private_key: PrivateKey, name: String) -> KeyPair {
-        KeyPair {
-            public: private_key.public(),
-            private: private_key,
+    /// Fails if key can't be prepared for signing
+    pub fn new(private_key: PrivateKey, name: String) -> Result<KeyPair> {
+        Ok(KeyPair {
+            pkey: private_key.to_pkey()?,
+            public: private_key.public()?,
+            private: Arc::new(private_key),
             name,
-        }
+            ephemeral: false,
+        })
     }
 
     pub fn from_wire<R: Read>(r: &mut R) -> Result<KeyPair> {
         let key = PrivateKey::from_wire(r).wrap_err("read key")?;
         let name = r.read_string_utf8()?;
-        Ok(KeyPair {
-            public: key.public(),
-            private: key,
-            name,
-        })
+        KeyPair::new(key, name)
+    }
+
+    pub fn to_wire<W: Write>(&self, w: &mut W) -> Result<()> {
+        self.private.to_wire(w).wrap_err("write key")?;
+        w.write_string(&self.name)?;
+        Ok(())
     }
 
     pub fn private(&self) -> &PrivateKey {
         &self.private
     }
 
+    pub fn sign(&self, msg: &[u8], flags: u32) -> Result<Vec<u8>> {
+        self.private.sign_with(&self.pkey, msg, flags)
+    }
+
     pub fn public(&self) -> &PublicKey {
         &self.public
     }
@@ -160,4 +470,16 @@ impl KeyPair {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    pub fn set_ephemeral(&mut self, ephemeral: bool) {
+        self.ephemeral = ephemeral;
+    }
 }
@@ -1,5 +1,7 @@
 use std::fmt;
 
+use openssl::ec::EcKey;
+use openssl::pkey::PKey;
 use openssl::rsa::Rsa;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::MapAccess;
@@ -9,11 +11,15 @@ use crate::key::{KeyPair, PrivateKey};
 
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "lowercase")]
+#[allow(clippy::upper_case_acronyms)]
 enum KeyType {
     RSA,
+    ECDSA,
+    Ed25519,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Holds private key, so it must never get Debug
+#[derive(Deserialize, Serialize)]
 struct KeyConfig {
     #[serde(rename = "type")]
     key_type: KeyType,
@@ -47,8 +53,16 @@ pub fn deserialize_key_pairs<'de, D: Deserializer<'de>>(
                         Rsa::private_key_from_pem(config.data.as_bytes())
                             .or(Err(A::Error::custom("invalid data")))?,
                     ),
+                    ECDSA => PrivateKey::ECDSA(
+                        EcKey::private_key_from_pem(config.data.as_bytes())
+                            .or(Err(A::Error::custom("invalid data")))?,
+                    ),
+                    Ed25519 => PrivateKey::Ed25519(
+                        PKey::private_key_from_pem(config.data.as_bytes())
+                            .or(Err(A::Error::custom("invalid data")))?,
+                    ),
                 };
-                v.push(KeyPair::new(private, key));
+                v.push(KeyPair::new(private, key).or(Err(A::Error::custom("invalid key")))?);
             }
             Ok(v)
         }
@@ -58,19 +72,27 @@ pub fn deserialize_key_pairs<'de, D: Deserializer<'de>>(
 }
 
 pub fn serialize_key_pairs<S: Serializer>(
-    v: &Vec<KeyPair>,
+    v: &[KeyPair],
     serializer: S,
 ) -> std::result::Result<S::Ok, S::Error> {
     let mut map = serializer.serialize_map(None)?;
-    for key_pair in v {
+    for key_pair in v.iter().filter(|key_pair| !key_pair.is_ephemeral()) {
         use PrivateKey::*;
         map.serialize_key(key_pair.name())?;
 
-        let (key_type, data) = match &key_pair.private {
+        let (key_type, data) = match key_pair.private() {
             RSA(rsa) => (
                 KeyType::RSA,
                 String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap(),
             ),
+            ECDSA(ec) => (
+                KeyType::ECDSA,
+                String::from_utf8(ec.private_key_to_pem().unwrap()).unwrap(),
+            ),
+            Ed25519(pkey) => (
+                KeyType::Ed25519,
+                String::from_utf8(pkey.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+            ),
         };
         map.serialize_value(&KeyConfig { key_type, data })?;
     }
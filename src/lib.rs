@@ -0,0 +1,28 @@
+//! ssh-agent/pageant implementation.
+//!
+//! Library part of ssh-keys: agent protocol handling, async server core, management protocol,
+//! audit log, usage stats, key codecs, wire helpers and client.
+//! Platform listeners (named pipe, pageant, unix sockets) are available with `platform` feature.
+
+pub use agent::{Agent, ConfirmationProvider, RequesterInfo, RequestInfo};
+pub use key::{KeyPair, PrivateKey, PublicKey};
+
+pub mod agent;
+pub mod audit;
+pub mod client;
+pub mod config;
+pub mod control;
+pub mod key;
+pub mod logging;
+pub mod server;
+pub mod stats;
+pub mod utils;
+
+#[cfg(all(windows, feature = "platform"))]
+#[path = "./platform/win/mod.rs"]
+pub mod platform;
+#[cfg(all(unix, feature = "platform"))]
+#[path = "platform/unix/mod.rs"]
+pub mod platform;
+
+pub const NAME: &str = "ssh-keys";
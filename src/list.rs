@@ -0,0 +1,78 @@
+use clap::ArgMatches;
+use serde::Serialize;
+use wrapperrs::{Result, ResultExt};
+
+use ssh_keys::agent::Agent;
+use ssh_keys::audit::format_timestamp;
+use ssh_keys::client::AgentClient;
+use ssh_keys::key::PublicKey;
+use ssh_keys::stats::KeyStats;
+
+#[derive(Serialize)]
+struct KeyInfo {
+    name: String,
+    #[serde(rename = "type")]
+    key_type: String,
+    bits: u32,
+    fingerprint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<KeyStats>,
+}
+
+impl KeyInfo {
+    fn new(agent: &Agent, name: &str, key: &PublicKey) -> KeyInfo {
+        KeyInfo {
+            name: name.to_string(),
+            key_type: key.key_type().to_string(),
+            bits: key.bits(),
+            fingerprint: key.fingerprint(),
+            usage: agent.key_stats(key),
+        }
+    }
+
+    fn last_used(&self) -> String {
+        match self.usage.as_ref().and_then(|usage| usage.last_used) {
+            Some(last_used) => format_timestamp(last_used),
+            None => "never".to_string(),
+        }
+    }
+}
+
+pub fn list(agent: &Agent, opts: &ArgMatches) -> Result<()> {
+    let client = if opts.is_present("config") {
+        None
+    } else if opts.is_present("agent") {
+        Some(AgentClient::connect()?)
+    } else {
+        AgentClient::connect_running()?
+    };
+
+    let keys: Vec<_> = if let Some(mut client) = client {
+        client.request_identities().wrap_err("list agent keys")?
+            .iter()
+            .map(|identity| KeyInfo::new(agent, &identity.comment, &identity.key))
+            .collect()
+    } else {
+        agent.config()
+            .keys
+            .iter()
+            .map(|key_pair| KeyInfo::new(agent, key_pair.name(), key_pair.public()))
+            .collect()
+    };
+
+    if opts.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&keys)?);
+        return Ok(());
+    }
+
+    let name_width = keys.iter().map(|key| key.name.len()).max().unwrap_or(0);
+    let type_width = keys.iter().map(|key| key.key_type.len()).max().unwrap_or(0);
+    // Stats are read from state file, which running agent keeps up to date
+    for key in &keys {
+        let signatures = key.usage.as_ref().map_or(0, |usage| usage.signatures);
+        println!("{:name_width$}  {:type_width$}  {:>5}  {}  {:>20}  {:>6}", key.name,
+                 key.key_type, key.bits, key.fingerprint, key.last_used(), signatures,
+                 name_width = name_width, type_width = type_width);
+    }
+    Ok(())
+}
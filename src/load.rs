@@ -0,0 +1,89 @@
+use std::io::{Read, stdin};
+
+use clap::{Arg, ArgMatches};
+use wrapperrs::{Error, Result, ResultExt};
+
+use ssh_keys::client::AgentClient;
+use ssh_keys::key::{KeyPair, PrivateKey};
+
+/// Arguments of subcommands accepting ephemeral keys
+pub fn key_source_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("key-env").long("key-env").takes_value(true).value_name("var")
+            .multiple(true).number_of_values(1)
+            .help("load ephemeral key from environment variable, variable is cleared"),
+        Arg::with_name("key-fd").long("key-fd").takes_value(true).value_name("fd")
+            .multiple(true).number_of_values(1)
+            .help("load ephemeral key from file descriptor"),
+        Arg::with_name("key-stdin").long("key-stdin").help("load ephemeral key from stdin"),
+    ]
+}
+
+fn import(data: &str, source: String) -> Result<KeyPair> {
+    let (key, comment) = PrivateKey::import(data)
+        .wrap_err(&format!("import key from {}", source))?;
+    let mut key_pair = KeyPair::new(key, comment.unwrap_or(source))?;
+    key_pair.set_ephemeral(true);
+    Ok(key_pair)
+}
+
+/// Descriptor stays open, it's owned by whoever passed it
+#[cfg(unix)]
+fn read_fd(fd: i32) -> Result<String> {
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::FromRawFd;
+
+    let mut data = String::new();
+    ManuallyDrop::new(unsafe { File::from_raw_fd(fd) }).read_to_string(&mut data)
+        .wrap_err(&format!("read fd {}", fd))?;
+    Ok(data)
+}
+
+#[cfg(windows)]
+fn read_fd(_fd: i32) -> Result<String> {
+    Err(Error::new("--key-fd is only supported on unix").into())
+}
+
+/// Reads keys from sources given in `key_source_args`, keys are marked ephemeral and named
+/// after their comment or source
+pub fn load_keys(opts: &ArgMatches) -> Result<Vec<KeyPair>> {
+    let mut keys = Vec::new();
+    for var in opts.values_of("key-env").into_iter().flatten() {
+        let data = std::env::var(var).wrap_err(&format!("read {}", var))?;
+        // So that child processes don't see the key
+        std::env::remove_var(var);
+        keys.push(import(&data, var.to_string())?);
+    }
+    let mut fds = Vec::new();
+    for fd in opts.values_of("key-fd").into_iter().flatten() {
+        let fd: i32 = fd.parse().ok().filter(|fd| *fd >= 0)
+            .ok_or(Error::new(&format!("invalid fd: {}", fd)))?;
+        if fds.contains(&fd) {
+            return Err(Error::new(&format!("fd {} is given more than once", fd)).into());
+        }
+        fds.push(fd);
+        keys.push(import(&read_fd(fd)?, format!("fd-{}", fd))?);
+    }
+    if opts.is_present("key-stdin") {
+        let mut data = String::new();
+        stdin().read_to_string(&mut data).wrap_err("read stdin")?;
+        keys.push(import(&data, "stdin".to_string())?);
+    }
+    Ok(keys)
+}
+
+pub fn load(opts: &ArgMatches) -> Result<()> {
+    let keys = load_keys(opts)?;
+    if keys.is_empty() {
+        return Err(Error::new("no key sources given").into());
+    }
+
+    let mut client = AgentClient::connect().wrap_err("connect to agent")?;
+    for key_pair in keys {
+        let name = key_pair.name().to_string();
+        client.add_ephemeral_identity(key_pair).wrap_err(&format!("add key {}", name))?;
+        println!("Loaded key {}", name);
+    }
+    Ok(())
+}
@@ -1,47 +1,123 @@
-use std::fs::{create_dir_all, OpenOptions};
+use std::fs::create_dir_all;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use log::error;
 use log::info;
+use log::warn;
 use log::LevelFilter;
-use simplelog::{CombinedLogger, ConfigBuilder, SimpleLogger, WriteLogger};
-use wrapperrs::{Result, ResultExt};
+use simplelog::{CombinedLogger, ConfigBuilder, SharedLogger, SimpleLogger, WriteLogger};
+use ssh_keys::{Agent, NAME, platform};
+use ssh_keys::audit::AuditLog;
+use ssh_keys::config::{Config, load_config};
+use ssh_keys::logging::{LoggingConfig, LogLevel, RedactingLogger, RotatingFile};
+use ssh_keys::platform::config_dir;
+use ssh_keys::stats::UsageStats;
+use wrapperrs::{ErrorExt, Result, ResultExt};
 
+use approve::approve;
 use copy_id::copy_id;
+use daemon::daemon;
+use exec::exec;
+use generate::generate;
+use list::list;
+use load::load;
+use manage::{control, remove, rename};
+use pubkey::pubkey;
+use query::audit;
+use relay::relay;
 
-use crate::agent::Agent;
-use crate::config::load_config;
-use crate::platform::config_dir;
-
-mod agent;
-mod config;
+mod approve;
 mod copy_id;
-mod key;
-mod utils;
+mod daemon;
+mod exec;
+mod generate;
+mod list;
+mod load;
+mod manage;
+mod pubkey;
+mod query;
+mod relay;
+
+/// Global flag given after subcommand is only present in subcommand matches
+fn is_present(opts: &ArgMatches, name: &str) -> bool {
+    opts.is_present(name) || opts.subcommand().1.is_some_and(|opts| opts.is_present(name))
+}
 
-#[cfg(windows)]
-#[path = "./platform/win/mod.rs"]
-mod platform;
-#[cfg(unix)]
-#[path = "platform/unix/mod.rs"]
-mod platform;
+fn init_logger(opts: &ArgMatches, logging: &LoggingConfig) {
+    let logger_config = ConfigBuilder::new()
+        .set_location_level(LevelFilter::Error)
+        .build();
+    let verbose = is_present(opts, "verbose");
+    // Output of agent subcommand is evaluated by shell, exec shares terminal
+    // with the command and relay uses stdout for messages, so only errors go to stderr
+    let term_logger: Box<dyn SharedLogger> = match opts.subcommand() {
+        ("agent", Some(agent_opts)) if verbose || agent_opts.is_present("debug") => {
+            WriteLogger::new(LevelFilter::Trace, logger_config.clone(), std::io::stderr())
+        }
+        ("agent", _) | ("exec", _) | ("relay", _) => {
+            let level = if verbose { LevelFilter::Trace } else { LevelFilter::Error };
+            WriteLogger::new(level, logger_config.clone(), std::io::stderr())
+        }
+        _ => {
+            let level = if verbose {
+                LevelFilter::Trace
+            } else if is_present(opts, "quiet") {
+                LevelFilter::Error
+            } else {
+                logging.console_level.into()
+            };
+            SimpleLogger::new(level, logger_config.clone())
+        }
+    };
 
-const NAME: &str = "ssh-keys";
+    let mut loggers = vec![term_logger];
+    if logging.file_level != LogLevel::Off && !is_present(opts, "in-memory") {
+        let path = logging.path.clone().unwrap_or_else(|| config_dir().join("trace.log"));
+        let log_file = RotatingFile::new(path, logging.max_size, logging.max_files);
+        loggers.push(WriteLogger::new(logging.file_level.into(), logger_config, log_file));
+    }
+    let logger = CombinedLogger::new(loggers);
+    log::set_max_level(logger.level());
+    log::set_boxed_logger(Box::new(RedactingLogger::new(*logger))).expect("init logger");
+}
 
 fn main() {
     if let Err(err) = (|| {
-        let logger_config = ConfigBuilder::new()
-            .set_location_level(LevelFilter::Error)
-            .build();
-        create_dir_all(config_dir()).expect("create config dir");
-        let log_file = OpenOptions::new().write(true).append(true).create(true)
-            .open(config_dir().join("trace.log")).expect("create log file");
-        CombinedLogger::init(vec![
-            SimpleLogger::new(LevelFilter::Trace, logger_config.clone()),
-            WriteLogger::new(LevelFilter::Trace, logger_config, log_file)
-        ]).expect("init logger");
-
         let opts = App::new(NAME)
+            .arg(Arg::with_name("in-memory").long("in-memory").global(true)
+                .help("don't read or write config, keys are kept in memory only"))
+            .arg(Arg::with_name("verbose").long("verbose").short("-v").global(true)
+                .conflicts_with("quiet").help("log everything to terminal"))
+            .arg(Arg::with_name("quiet").long("quiet").short("-q").global(true)
+                .help("log only errors to terminal"))
+            .subcommand(
+                App::new("agent").about("run as ssh-agent compatible daemon")
+                    .arg(Arg::with_name("sh").short("-s").help("print sh commands"))
+                    .arg(Arg::with_name("csh").short("-c").conflicts_with("sh")
+                        .help("print csh commands"))
+                    .arg(Arg::with_name("address").short("-a").takes_value(true)
+                        .value_name("bind_address").help("socket path"))
+                    .arg(Arg::with_name("foreground").short("-D").help("don't fork"))
+                    .arg(Arg::with_name("debug").short("-d")
+                        .help("don't fork and log to stderr"))
+                    .arg(Arg::with_name("idle-exit").long("idle-exit").takes_value(true)
+                        .value_name("seconds").help("exit when no client connects for this long"))
+                    .arg(Arg::with_name("kill").short("-k")
+                        .conflicts_with_all(&["foreground", "debug"])
+                        .help("kill running agent"))
+                    .args(&load::key_source_args()),
+            )
+            .subcommand(
+                App::new("control").about("manage running agent")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(App::new("reload").about("reload config"))
+                    .subcommand(App::new("lock").about("lock agent with passphrase"))
+                    .subcommand(App::new("unlock"))
+                    .subcommand(App::new("approvals").about("list remembered approvals"))
+                    .subcommand(App::new("revoke").about("revoke remembered approval")
+                        .arg(Arg::with_name("id").required(true)))
+                    .subcommand(App::new("events").about("print agent events as JSON lines")),
+            )
             .subcommand(
                 App::new("copy-id")
                     .arg(Arg::with_name("username@host").required(true))
@@ -49,14 +125,124 @@ fn main() {
                     .arg(Arg::with_name("port").short("-p").default_value("22"))
                     .arg(Arg::with_name("erase").short("-e").help("Remove all keys")),
             )
+            .subcommand(
+                App::new("exec").about("run command with a private agent holding selected keys")
+                    .arg(Arg::with_name("key").long("key").short("-k").takes_value(true)
+                        .multiple(true).number_of_values(1).help("key name or fingerprint"))
+                    .args(&load::key_source_args())
+                    .arg(Arg::with_name("command").multiple(true).required(true).last(true)),
+            )
+            .subcommand(
+                App::new("generate")
+                    .arg(Arg::with_name("type").long("type").short("-t")
+                        .possible_values(&["rsa", "ed25519", "ecdsa"]).default_value("rsa"))
+                    .arg(Arg::with_name("bits").long("bits").short("-b").takes_value(true)
+                        .help("key size, rsa keys are at least 2048 bits (default 3072)"))
+                    .arg(Arg::with_name("name").long("name").short("-n").takes_value(true)
+                        .required(true).help("key name"))
+                    .arg(Arg::with_name("out").long("out").short("-o").takes_value(true)
+                        .help("write public key to file")),
+            )
+            .subcommand(
+                App::new("list")
+                    .arg(Arg::with_name("agent").long("agent").short("-a")
+                        .help("fail if agent is not running"))
+                    .arg(Arg::with_name("config").long("config").short("-c")
+                        .conflicts_with("agent").help("read config instead of querying agent"))
+                    .arg(Arg::with_name("json").long("json")),
+            )
+            .subcommand(
+                App::new("approve").about("decide sign requests of running agent")
+                    .arg(Arg::with_name("watch").long("watch").short("-w")
+                        .help("keep deciding requests instead of exiting after the first"))
+                    .arg(Arg::with_name("remember").long("remember").short("-r")
+                        .takes_value(true).value_name("minutes").default_value("10")
+                        .help("how long [r] approves further requests of the same requester, \
+                               only offered if requester is known")),
+            )
+            .subcommand(
+                App::new("audit").about("query audit log")
+                    .arg(Arg::with_name("key").long("key").short("-k").takes_value(true)
+                        .help("key name or fingerprint"))
+                    .arg(Arg::with_name("since").long("since").takes_value(true)
+                        .help("seconds since epoch or relative time, e.g. 12h or 7d"))
+                    .arg(Arg::with_name("until").long("until").takes_value(true))
+                    .arg(Arg::with_name("requester").long("requester").short("-r")
+                        .takes_value(true).help("part of requester description"))
+                    .arg(Arg::with_name("json").long("json").help("print records as JSON lines")),
+            )
+            .subcommand(
+                App::new("load").about("add ephemeral keys to running agent")
+                    .args(&load::key_source_args()),
+            )
+            .subcommand(
+                App::new("relay").about("relay agent messages between stdio and agent")
+                    .arg(Arg::with_name("address").long("address").short("-a")
+                        .takes_value(true).help("agent socket or pipe to connect to"))
+                    .arg(Arg::with_name("listen").long("listen").short("-l").takes_value(true)
+                        .value_name("socket").requires("command")
+                        .help("serve socket, relaying each connection over a command"))
+                    .arg(Arg::with_name("command").multiple(true).last(true)
+                        .help("relay command, e.g. ssh-keys.exe relay")),
+            )
+            .subcommand(
+                App::new("remove")
+                    .arg(Arg::with_name("key").required(true).help("key name or fingerprint")),
+            )
+            .subcommand(
+                App::new("rename")
+                    .arg(Arg::with_name("key").required(true).help("key name or fingerprint"))
+                    .arg(Arg::with_name("new").required(true).help("new key name")),
+            )
+            .subcommand(
+                App::new("pubkey")
+                    .arg(Arg::with_name("name").required_unless("import").help("key name"))
+                    .arg(Arg::with_name("import").long("import").short("-i").takes_value(true)
+                        .conflicts_with("name").help("convert public key from file, - for stdin"))
+                    .arg(Arg::with_name("format").long("format").short("-f")
+                        .possible_values(&["openssh", "rfc4716", "pkcs8", "pem"])
+                        .default_value("openssh")),
+            )
             .get_matches();
 
-        let config = load_config().wrap_err("load config")?;
-        config.save()?;
-        let agent = Agent::new(config);
+        let in_memory = is_present(&opts, "in-memory");
+        let config = if in_memory {
+            Ok(Config::default())
+        } else {
+            create_dir_all(config_dir()).expect("create config dir");
+            load_config(&config_dir().join("config.toml"))
+        };
+        // Logger is configured by config, so errors loading it are reported afterwards
+        init_logger(&opts, &config.as_ref().map(|config| config.logging.clone())
+            .unwrap_or_default());
+        let config = config.wrap_err("load config")?;
+        let agent = Agent::new(config, Box::new(platform::ask_confirmation));
+        let agent = if in_memory {
+            agent
+        } else {
+            let path = config_dir().join("stats.json");
+            let stats = UsageStats::load(&path).unwrap_or_else(|err| {
+                warn!("{}", err.wrap("load usage stats, starting over"));
+                UsageStats::empty(&path)
+            });
+            agent.with_audit_log(AuditLog::new(config_dir().join("audit.log")))
+                .with_usage_stats(stats)
+        };
 
         match opts.subcommand() {
+            ("agent", opts) => daemon(agent, opts.unwrap()),
+            ("approve", opts) => approve(opts.unwrap()),
+            ("audit", opts) => audit(opts.unwrap()),
+            ("control", opts) => control(opts.unwrap()),
             ("copy-id", opts) => copy_id(&agent, opts.unwrap()),
+            ("exec", opts) => exec(&agent, opts.unwrap()),
+            ("generate", opts) => generate(&agent, opts.unwrap()),
+            ("load", opts) => load(opts.unwrap()),
+            ("list", opts) => list(&agent, opts.unwrap()),
+            ("pubkey", opts) => pubkey(&agent, opts.unwrap()),
+            ("relay", opts) => relay(opts.unwrap()),
+            ("remove", opts) => remove(&agent, opts.unwrap()),
+            ("rename", opts) => rename(&agent, opts.unwrap()),
             _ => {
                 info!("Started");
                 platform::serve(agent)
@@ -66,5 +252,6 @@ fn main() {
         Ok(()) as Result<()>
     })() {
         error!("{}", err);
+        std::process::exit(1);
     };
 }
@@ -0,0 +1,125 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+use wrapperrs::{Error, Result, ResultExt};
+
+use ssh_keys::agent::Agent;
+use ssh_keys::client::AgentClient;
+use ssh_keys::control::{ControlClient, ControlCommand, ResponseBody};
+use ssh_keys::key::{FingerprintHash, PublicKey};
+use ssh_keys::logging::Secret;
+
+/// Finds key by name or by SHA256/MD5 fingerprint
+pub fn find_key<'a, I>(keys: I, pattern: &str) -> Result<(String, PublicKey)>
+    where I: Iterator<Item=(&'a str, &'a PublicKey)>
+{
+    keys.filter(|(name, key)| {
+        *name == pattern
+            || key.fingerprint_with(FingerprintHash::SHA256) == pattern
+            || key.fingerprint_with(FingerprintHash::MD5) == pattern
+    })
+        .map(|(name, key)| (name.to_string(), key.clone()))
+        .next()
+        .ok_or(Error::new(&format!("key not found: {}", pattern)).into())
+}
+
+/// Applies change through running agent, so its in-memory config stays consistent,
+/// or directly to config file if agent isn't running
+fn modify<C, L>(agent: &Agent, pattern: &str, via_client: C, locally: L) -> Result<String>
+    where
+        C: FnOnce(&mut AgentClient, &PublicKey) -> Result<()>,
+        L: FnOnce(&Agent, &PublicKey) -> Result<()>,
+{
+    match AgentClient::connect_running()? {
+        Some(mut client) => {
+            let identities = client.request_identities().wrap_err("list agent keys")?;
+            let (name, key) = find_key(
+                identities.iter().map(|identity| (identity.comment.as_str(), &identity.key)),
+                pattern,
+            )?;
+            via_client(&mut client, &key).wrap_err("update running agent")?;
+            Ok(name)
+        }
+        None => {
+            let (name, key) = find_key(
+                agent.config().keys.iter().map(|key_pair| (key_pair.name(), key_pair.public())),
+                pattern,
+            )?;
+            locally(agent, &key).wrap_err("update config")?;
+            Ok(name)
+        }
+    }
+}
+
+pub fn remove(agent: &Agent, opts: &ArgMatches) -> Result<()> {
+    let name = modify(
+        agent,
+        opts.value_of("key").unwrap(),
+        |client, key| client.remove_identity(key),
+        |agent, key| agent.remove_key(key).map(|_| ()),
+    )?;
+    println!("Key removed: {}", name);
+    Ok(())
+}
+
+pub fn rename(agent: &Agent, opts: &ArgMatches) -> Result<()> {
+    let new_name = opts.value_of("new").unwrap();
+    let name = modify(
+        agent,
+        opts.value_of("key").unwrap(),
+        |client, key| client.rename(key, new_name),
+        |agent, key| agent.rename_key(key, new_name.to_string()),
+    )?;
+    println!("Key renamed: {} -> {}", name, new_name);
+    Ok(())
+}
+
+fn read_passphrase() -> Result<Secret<String>> {
+    Ok(Secret(rpassword::read_password_from_tty(Some("Passphrase: "))?))
+}
+
+fn print_approvals(client: &mut ControlClient) -> Result<()> {
+    let approvals = match client.request(ControlCommand::Approvals)? {
+        ResponseBody::Approvals { approvals } => approvals,
+        _ => return Err(Error::new("unexpected response").into()),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    for approval in approvals {
+        println!("{:>4}  {}  {}  {}  expires in {}s", approval.id, approval.key_name,
+                 approval.fingerprint, approval.requester.as_deref().unwrap_or("unknown"),
+                 approval.expires.saturating_sub(now));
+    }
+    Ok(())
+}
+
+/// Commands of running agent's control socket
+pub fn control(opts: &ArgMatches) -> Result<()> {
+    let mut client = ControlClient::connect().wrap_err("connect to agent control socket")?;
+    match opts.subcommand() {
+        ("reload", _) => {
+            client.request(ControlCommand::Reload)?;
+        }
+        ("lock", _) => {
+            let passphrase = read_passphrase()?;
+            client.request(ControlCommand::Lock { passphrase })?;
+        }
+        ("unlock", _) => {
+            let passphrase = read_passphrase()?;
+            client.request(ControlCommand::Unlock { passphrase })?;
+        }
+        ("approvals", _) => print_approvals(&mut client)?,
+        ("revoke", opts) => {
+            let id = opts.unwrap().value_of("id").unwrap().parse()
+                .map_err(|_| Error::new("invalid approval id"))?;
+            client.request(ControlCommand::Revoke { id })?;
+        }
+        ("events", _) => {
+            client.subscribe()?;
+            loop {
+                println!("{}", serde_json::to_string(&client.next_event()?)?);
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
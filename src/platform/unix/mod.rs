@@ -1,45 +1,223 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::fs::remove_file;
-use std::io::{Read, Write};
+use std::fs::{DirBuilder, remove_file, set_permissions, symlink_metadata};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
 use wrapperrs::{Error, ErrorExt, Result, ResultExt};
 
-use crate::agent::{Agent, SSH_AGENT_FAILURE};
+use signals::handle_signals;
+
+use crate::agent::{Agent, RequestInfo};
+use crate::config::Config;
+use crate::control::run_control;
+use crate::key::KeyPair;
+use crate::NAME;
+use crate::server::{BoxFuture, ConnectionLimits, Listener, Service, UnixSocketListener};
+use crate::utils::Finally;
 
-const SOCK_PATH: &str = "/tmp/auth_sock";
+mod signals;
+pub mod systemd;
 
-fn connection_handler(agent: Arc<Mutex<Box<Agent>>>, mut stream: UnixStream) -> Result<()> {
-    loop {
-        let len = stream.read_u32::<BigEndian>()?;
+/// How long in-flight requests can take after shutdown is requested
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
 
-        let mut content = vec![0; len as usize];
-        stream.read_exact(&mut content).wrap_err("read content")?;
+pub fn show_error(err: Box<dyn std::error::Error>) {
+    eprintln!("Error: {}", err);
+}
+
+pub fn config_dir() -> PathBuf {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(NAME)
+}
 
-        let mut agent = agent.lock().unwrap();
-        let resp = match agent.handle_request(&content) {
-            Ok(resp) => resp,
-            Err(err) => {
-                eprintln!("{}", err.wrap("error handling message"));
-                vec![SSH_AGENT_FAILURE]
+/// `$XDG_RUNTIME_DIR/ssh-keys/agent.sock`, or per-user directory in `/tmp`
+pub fn unix_socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(|dir| PathBuf::from(dir).join(NAME))
+        .unwrap_or_else(|| PathBuf::from(format!("/tmp/{}-{}", NAME, unsafe { libc::getuid() })))
+        .join("agent.sock")
+}
+
+/// Binds agent socket accessible only to current user. Socket left by agent that didn't
+/// exit cleanly is replaced, but not a socket of running agent
+pub fn bind_socket(path: &Path) -> Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)
+            .wrap_err(&format!("create {}", dir.display()))?;
+        // Directory in shared /tmp could have been created by another user beforehand
+        let metadata = symlink_metadata(dir).wrap_err(&format!("stat {}", dir.display()))?;
+        if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() }
+            || metadata.mode() & 0o777 != 0o700 {
+            return Err(Error::new(&format!("{} must be a directory owned by current user \
+                                            with mode 0700", dir.display())).into());
+        }
+    }
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::new(&format!("agent is already running on {}", path.display()))
+                .into());
+        }
+        remove_file(path).wrap_err("remove stale socket")?;
+    }
+
+    let listener = UnixListener::bind(path).wrap_err(&format!("bind {}", path.display()))?;
+    set_permissions(path, PermissionsExt::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Management socket of agent listening on `socket_path`
+pub fn control_socket_path(socket_path: &Path) -> PathBuf {
+    socket_path.with_extension("ctl")
+}
+
+/// Management socket, connections of other users are rejected even if socket permissions
+/// allow them
+pub struct ControlListener(tokio::net::UnixListener);
+
+impl ControlListener {
+    /// Has to be called within tokio runtime
+    pub fn from_std(listener: UnixListener) -> Result<ControlListener> {
+        listener.set_nonblocking(true)?;
+        Ok(ControlListener(tokio::net::UnixListener::from_std(listener)?))
+    }
+}
+
+impl Listener for ControlListener {
+    type Stream = tokio::net::UnixStream;
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Self::Stream, RequestInfo)>> {
+        Box::pin(async move {
+            let uid = unsafe { libc::getuid() };
+            loop {
+                let (stream, _) = self.0.accept().await?;
+                match stream.peer_cred() {
+                    Ok(cred) if cred.uid() == uid => {
+                        return Ok((stream, RequestInfo { channel: "Control", requester: None }));
+                    }
+                    Ok(cred) => warn!("control connection of other user rejected uid={}",
+                                      cred.uid()),
+                    Err(err) => warn!("control connection rejected, no peer credentials: {}",
+                                      err),
+                }
             }
-        };
-        stream.write_u32::<BigEndian>(resp.len() as u32)?;
-        stream.write_all(&resp)?;
+        })
     }
 }
 
-pub fn serve(agent: Box<Agent>) -> Result<()> {
-    let agent_mtx = Arc::new(Mutex::new(agent));
+/// There is no confirmation UI on unix, every request is allowed
+pub fn ask_confirmation(key_pair: &KeyPair, req_info: &RequestInfo, _config: &Config) -> bool {
+    warn!("no confirmation provider, allowing key={} channel={}", key_pair.name(),
+          req_info.channel);
+    true
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ServeOptions {
+    /// Socket file removed on exit, not set for sockets owned by service manager
+    pub socket_path: Option<PathBuf>,
+    /// Exit after no client was connected for this long
+    pub idle_exit: Option<Duration>,
+    /// Management socket to bind, removed on exit
+    pub control_path: Option<PathBuf>,
+}
 
-    remove_file(SOCK_PATH);
-    let sock_server = UnixListener::bind(SOCK_PATH).wrap_err("bind")?;
-    for stream in sock_server.incoming() {
-        let agent_mtx = agent_mtx.clone();
-        std::thread::spawn(move || {
-            connection_handler(agent_mtx.clone(), stream.unwrap());
+/// Serves sockets passed by systemd, or binds default socket
+pub fn serve(agent: Agent) -> Result<()> {
+    let listeners = systemd::listen_fds()?;
+    if !listeners.is_empty() {
+        let control_path = listeners[0].local_addr().ok()
+            .and_then(|addr| addr.as_pathname().map(control_socket_path));
+        return serve_listeners(agent, listeners, &ServeOptions {
+            control_path,
+            ..ServeOptions::default()
         });
     }
-    Ok(())
+
+    let socket_path = unix_socket_path();
+    let listener = bind_socket(&socket_path)?;
+    serve_listeners(agent, vec![listener], &ServeOptions {
+        control_path: Some(control_socket_path(&socket_path)),
+        socket_path: Some(socket_path),
+        ..ServeOptions::default()
+    })
+}
+
+/// Serves until SIGTERM, SIGINT or idle timeout
+pub fn serve_listeners(agent: Agent, listeners: Vec<UnixListener>, options: &ServeOptions)
+                       -> Result<()> {
+    let control = match &options.control_path {
+        Some(control_path) => Some(bind_socket(control_path)?),
+        None => None,
+    };
+    let _socket = Finally::new(|| {
+        for path in options.socket_path.iter().chain(&options.control_path) {
+            let _ = remove_file(path);
+        }
+    });
+    let service = Service::new(Arc::new(agent), ConnectionLimits::default());
+
+    let runtime = service.runtime()?;
+    runtime.block_on(async move {
+        let mut runs = Vec::new();
+        for listener in listeners {
+            if let Ok(addr) = listener.local_addr() {
+                info!("listening on {:?}", addr.as_pathname());
+            }
+            let listener = UnixSocketListener::from_std(listener)?;
+            let service = service.clone();
+            runs.push(tokio::spawn(async move {
+                if let Err(err) = service.clone().run(listener).await {
+                    error!("{}", err);
+                    service.shutdown();
+                }
+            }));
+        }
+
+        if let Some(control) = control {
+            let listener = ControlListener::from_std(control)?;
+            let service = service.clone();
+            tokio::spawn(async move {
+                if let Err(err) = run_control(service, listener).await {
+                    error!("{}", err.wrap("control socket"));
+                }
+            });
+        }
+
+        let flush_stats = {
+            let service = service.clone();
+            tokio::spawn(async move { service.flush_stats().await })
+        };
+        let signals = tokio::spawn(handle_signals(service.clone()));
+        let idle_exit = options.idle_exit.map(|period| {
+            let service = service.clone();
+            tokio::spawn(async move {
+                service.wait_inactive(period).await;
+                info!("no clients for {}s, exiting", period.as_secs());
+                service.shutdown();
+            })
+        });
+        systemd::notify_logged("READY=1");
+
+        for run in runs {
+            run.await?;
+        }
+        systemd::notify_logged("STOPPING=1");
+        if tokio::time::timeout(SHUTDOWN_GRACE, service.wait_idle()).await.is_err() {
+            warn!("shutdown grace period elapsed, closing remaining connections");
+        }
+        flush_stats.await?;
+        signals.abort();
+        if let Some(idle_exit) = idle_exit {
+            idle_exit.abort();
+        }
+        info!("Stopped");
+        Ok(())
+    })
 }
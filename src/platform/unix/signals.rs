@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use log::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+use wrapperrs::{ErrorExt, Result, ResultExt};
+
+use crate::server::Service;
+
+fn dump_status(service: &Service) {
+    let config = service.agent().config();
+    let stats = service.stats();
+    info!("status keys={} locked={} connections_active={} connections_total={} \
+           connections_rejected={}", config.keys.len(), service.agent().is_locked(),
+          stats.active, stats.total, stats.rejected);
+    for key_pair in &config.keys {
+        let public = key_pair.public();
+        info!("status key name={} type={} bits={} fingerprint={}", key_pair.name(),
+              public.type_name(), public.bits(), public.fingerprint());
+    }
+}
+
+async fn listen_signals(service: &Service) -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate()).wrap_err("SIGTERM")?;
+    let mut interrupt = signal(SignalKind::interrupt()).wrap_err("SIGINT")?;
+    let mut hangup = signal(SignalKind::hangup()).wrap_err("SIGHUP")?;
+    let mut user1 = signal(SignalKind::user_defined1()).wrap_err("SIGUSR1")?;
+
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => {
+                info!("SIGTERM received, shutting down");
+                break;
+            }
+            _ = interrupt.recv() => {
+                info!("SIGINT received, shutting down");
+                break;
+            }
+            _ = hangup.recv() => {
+                info!("SIGHUP received, reloading config");
+                if let Err(err) = service.agent().reload_config() {
+                    error!("{}", err.wrap("reload config"));
+                }
+            }
+            _ = user1.recv() => dump_status(service),
+        }
+    }
+    service.shutdown();
+    Ok(())
+}
+
+/// SIGTERM and SIGINT shut service down, SIGHUP reloads config, SIGUSR1 logs status
+pub async fn handle_signals(service: Arc<Service>) {
+    if let Err(err) = listen_signals(&service).await {
+        error!("{}", err.wrap("listen for signals"));
+    }
+}
@@ -0,0 +1,81 @@
+use std::env::{remove_var, var, var_os};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+
+use log::{info, warn};
+use wrapperrs::{Error, ErrorExt, Result, ResultExt};
+
+/// First descriptor passed by systemd, `SD_LISTEN_FDS_START`
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd socket activation, empty if process isn't socket activated.
+/// Activation variables are removed, so that child processes don't inherit them
+pub fn listen_fds() -> Result<Vec<UnixListener>> {
+    match var("LISTEN_PID") {
+        Ok(pid) if pid.parse::<u32>().ok() == Some(std::process::id()) => {}
+        _ => return Ok(vec![]),
+    }
+    let count: RawFd = var("LISTEN_FDS").wrap_err("LISTEN_FDS is not set")?
+        .parse().wrap_err("parse LISTEN_FDS")?;
+    let names = var("LISTEN_FDNAMES").unwrap_or_default();
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        remove_var(name);
+    }
+
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).map(|fd| -> Result<UnixListener> {
+        let name = names.next().filter(|name| !name.is_empty()).unwrap_or("unknown");
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error().wrap(&format!("fd {}", fd)).into());
+        }
+
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        let addr = listener.local_addr()
+            .wrap_err(&format!("fd {} name={} is not a unix socket", fd, name))?;
+        info!("socket activated fd={} name={} path={:?}", fd, name, addr.as_pathname());
+        Ok(listener)
+    }).collect()
+}
+
+/// Sends state to service manager, e.g. `READY=1`. Does nothing if `NOTIFY_SOCKET` isn't set
+pub fn notify(state: &str) -> Result<()> {
+    let path = match var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let path = path.as_bytes();
+
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(Error::new("invalid NOTIFY_SOCKET").into());
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    // Abstract namespace socket, std doesn't support these
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let path_offset = addr.sun_path.as_ptr() as usize - &addr as *const _ as usize;
+
+    let socket = UnixDatagram::unbound().wrap_err("create notify socket")?;
+    let sent = unsafe {
+        libc::sendto(socket.as_raw_fd(), state.as_ptr() as *const libc::c_void, state.len(), 0,
+                     &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                     (path_offset + path.len()) as libc::socklen_t)
+    };
+    if sent == -1 {
+        return Err(io::Error::last_os_error().wrap("notify service manager").into());
+    }
+    Ok(())
+}
+
+/// Same as [`notify`], only logs errors
+pub fn notify_logged(state: &str) {
+    if let Err(err) = notify(state) {
+        warn!("{}", err.wrap(&format!("send {}", state)));
+    }
+}
@@ -94,12 +94,15 @@ pub unsafe extern "system" fn dlg_proc(
             let (description_short, description_long) =
                 if let Some(RequesterInfo {
                                 description_short,
-                                description_long
+                                description_long,
+                                ..
                             }) = &confirmation.req_info.requester {
                     (description_short.as_str(), Some(description_long.as_str()))
                 } else { ("Unknown", None) };
             SetDlgItemTextW(dlg, 4, description_short.to_utf16_null().as_ptr());
-            SetDlgItemTextW(dlg, 6, confirmation.key_pair.name().to_utf16_null().as_ptr());
+            let key_description = format!("{} ({})", confirmation.key_pair.name(),
+                                          confirmation.key_pair.public().fingerprint());
+            SetDlgItemTextW(dlg, 6, key_description.as_str().to_utf16_null().as_ptr());
             SetDlgItemTextW(dlg, 8, confirmation.req_info.channel.to_utf16_null().as_ptr());
 
             let text_controls = 4..=8;
@@ -1,9 +1,11 @@
 use std::ffi::CString;
+use std::future::Future;
 use std::mem::MaybeUninit;
 use std::path::PathBuf;
 use std::ptr::null_mut;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
+use tokio::runtime::Runtime;
 use winapi::shared::minwindef::TRUE;
 use winapi::shared::windef::HWND;
 use winapi::shared::winerror::ERROR_ALREADY_EXISTS;
@@ -19,12 +21,15 @@ use wrapperrs::{Error, Result, ResultExt};
 pub use confirmation::ask_confirmation;
 use confirmation::show_dialog;
 use pageant::listen_pageant;
-use pipe::listen_named_pipe;
+use pipe::{AGENT_PIPE, PipeListener};
 use taskbar::{base_icon_data, create_taskbar_icon};
-use unix_socket::listen_unix_socket;
+use unix_socket::UnixSocketListener;
+pub use unix_socket::socket_path as unix_socket_path;
 use utils::{check_error, format_error, get_known_folder};
 
 use crate::agent::Agent;
+use crate::control::{CONTROL_PIPE, run_control};
+use crate::server::{ConnectionLimits, Service};
 use crate::NAME;
 
 mod confirmation;
@@ -55,18 +60,19 @@ pub fn config_dir() -> PathBuf {
     get_known_folder(FOLDERID_RoamingAppData).join(NAME)
 }
 
-fn listener<F>(agent: &Arc<Mutex<Agent>>, name: &'static str, f: F)
+fn exit_on_error(name: &'static str, result: Result<()>) {
+    if let Err(err) = result.wrap_err(name) {
+        show_error(err.into());
+        std::process::exit(1);
+    }
+}
+
+fn listener<F>(runtime: &Runtime, name: &'static str, f: F)
     where
-        F: FnOnce(Arc<Mutex<Agent>>) -> Result<()>,
+        F: Future<Output=Result<()>>,
         F: Send + 'static,
 {
-    let agent = agent.clone();
-    std::thread::spawn(move || {
-        if let Err(err) = f(agent).wrap_err(name) {
-            show_error(err.into());
-            std::process::exit(1);
-        }
-    });
+    runtime.spawn(async move { exit_on_error(name, f.await) });
 }
 
 pub fn serve(agent: Agent) -> Result<()> {
@@ -84,11 +90,31 @@ pub fn serve(agent: Agent) -> Result<()> {
         }
 
         MAIN_THREAD_ID = GetCurrentThreadId();
-        let agent = Arc::new(Mutex::new(agent));
-
-        listener(&agent, "listen_named_pipe", listen_named_pipe);
-        listener(&agent, "listen_pageant", listen_pageant);
-        listener(&agent, "listen_unix_socket", listen_unix_socket);
+        let agent = Arc::new(agent);
+        let service = Service::new(agent.clone(), ConnectionLimits::default());
+        let runtime = service.runtime()?;
+
+        let pipe_service = service.clone();
+        listener(&runtime, "listen_named_pipe", async move {
+            let listener = PipeListener::new(pipe_service.agent().clone(), AGENT_PIPE, "Pipe")?;
+            pipe_service.run(listener).await
+        });
+        let control_service = service.clone();
+        listener(&runtime, "listen_control", async move {
+            let listener = PipeListener::new(control_service.agent().clone(), CONTROL_PIPE,
+                                             "Control")?;
+            run_control(control_service, listener).await
+        });
+        let stats_service = service.clone();
+        runtime.spawn(async move { stats_service.flush_stats().await });
+        let unix_service = service.clone();
+        listener(&runtime, "listen_unix_socket", async move {
+            let listener = UnixSocketListener::bind()?;
+            unix_service.run(listener).await
+        });
+        // Pageant requests come as window messages, they are handled on its window thread
+        let pageant_service = service.clone();
+        std::thread::spawn(move || exit_on_error("listen_pageant", listen_pageant(pageant_service)));
 
         create_taskbar_icon(&agent)?;
 
@@ -115,6 +141,11 @@ pub fn serve(agent: Agent) -> Result<()> {
                 }
             }
         }
+
+        // Blocking accept of unix socket never finishes, so runtime isn't waited for
+        service.shutdown();
+        runtime.shutdown_background();
+        agent.flush_stats();
     }
     Ok(())
 }
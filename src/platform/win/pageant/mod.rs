@@ -1,8 +1,7 @@
 use std::ffi::CStr;
-use std::io::{Cursor, Read, Write};
 use std::mem::{MaybeUninit, transmute};
 use std::ptr::null_mut;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use log::error;
 use log::trace;
@@ -14,12 +13,12 @@ use winapi::um::memoryapi::{FILE_MAP_WRITE, MapViewOfFile, UnmapViewOfFile, Virt
 use winapi::um::winbase::OpenFileMappingA;
 use winapi::um::winnt::MEMORY_BASIC_INFORMATION;
 use winapi::um::winuser::{CreateWindowExA, DefWindowProcA, DispatchMessageA, FindWindowA, GetClassLongPtrA, GetMessageA, HWND_MESSAGE, MSG, PCOPYDATASTRUCT, RegisterClassA, SetClassLongPtrA, TranslateMessage, WM_COPYDATA, WNDCLASSA, WS_CAPTION};
-use wrapperrs::{Error, Result, ResultExt};
+use wrapperrs::{Error, ErrorExt, Result, ResultExt};
 
 use utils::find_memory_map_owner_process;
 
-use crate::agent::{Agent, RequestInfo};
-use crate::utils::{connection_handler, ReadWrite};
+use crate::agent::RequestInfo;
+use crate::server::Service;
 
 use super::check_error;
 use super::utils::collect_requester_info;
@@ -28,21 +27,6 @@ mod utils;
 
 const CLASS_NAME: *const i8 = "Pageant\0".as_ptr() as *const i8;
 
-struct BufReadWrite<'a> {
-    req: Cursor<&'a [u8]>,
-    resp: &'a mut Vec<u8>,
-}
-
-impl<'a> ReadWrite for BufReadWrite<'a> {
-    fn read(&mut self) -> &mut dyn Read {
-        &mut self.req
-    }
-
-    fn write(&mut self) -> &mut dyn Write {
-        &mut self.resp
-    }
-}
-
 extern "system" fn wnd_proc(window: HWND, msg: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
     unsafe {
         if msg == WM_COPYDATA {
@@ -50,7 +34,7 @@ extern "system" fn wnd_proc(window: HWND, msg: UINT, wparam: WPARAM, lparam: LPA
             let mapping_name = CStr::from_ptr(copydata.lpData as *const i8)
                 .to_str()
                 .unwrap();
-            trace!("pageant request {}", mapping_name);
+            trace!("pageant request");
 
             let mapping_file =
                 OpenFileMappingA(FILE_MAP_WRITE, FALSE, copydata.lpData as *const i8);
@@ -64,13 +48,11 @@ extern "system" fn wnd_proc(window: HWND, msg: UINT, wparam: WPARAM, lparam: LPA
             let mapping =
                 std::slice::from_raw_parts_mut(mapping_ptr as *mut u8, mapping_info.RegionSize);
 
-            let agent = &*(GetClassLongPtrA(window, 0) as *mut Arc<Mutex<Agent>>);
-            let agent = agent.clone();
+            let service = &*(GetClassLongPtrA(window, 0) as *mut Arc<Service>);
 
             let requester = find_memory_map_owner_process(mapping_name)
                 .and_then(|process_id| {
-                    let agent = agent.lock().unwrap();
-                    collect_requester_info(agent.config(), process_id).ok()
+                    collect_requester_info(&service.agent().config(), process_id).ok()
                 });
 
             let info = RequestInfo {
@@ -78,15 +60,10 @@ extern "system" fn wnd_proc(window: HWND, msg: UINT, wparam: WPARAM, lparam: LPA
                 requester,
             };
 
-            let mut resp = Vec::new();
-            connection_handler(agent, &mut BufReadWrite {
-                req: Cursor::new(mapping),
-                resp: &mut resp,
-            }, info);
-            if resp.len() > mapping.len() {
-                error!("resp len > req len");
-            } else {
-                copy_nonoverlapping(resp.as_ptr(), mapping.as_mut_ptr(), resp.len());
+            match service.handle_frame(mapping, &info) {
+                Ok(resp) if resp.len() > mapping.len() => error!("resp len > req len"),
+                Ok(resp) => copy_nonoverlapping(resp.as_ptr(), mapping.as_mut_ptr(), resp.len()),
+                Err(err) => error!("{}", err.wrap("pageant request")),
             }
 
             UnmapViewOfFile(mapping_ptr);
@@ -99,7 +76,7 @@ extern "system" fn wnd_proc(window: HWND, msg: UINT, wparam: WPARAM, lparam: LPA
     }
 }
 
-pub fn listen_pageant(agent: Arc<Mutex<Agent>>) -> Result<()> {
+pub fn listen_pageant(service: Arc<Service>) -> Result<()> {
     unsafe {
         if FindWindowA(CLASS_NAME, CLASS_NAME) != null_mut() {
             return Err(Error::new("Pageant already running").into());
@@ -108,7 +85,7 @@ pub fn listen_pageant(agent: Arc<Mutex<Agent>>) -> Result<()> {
         let mut wndclass: WNDCLASSA = MaybeUninit::zeroed().assume_init();
         wndclass.lpfnWndProc = Some(wnd_proc);
         wndclass.lpszClassName = CLASS_NAME;
-        wndclass.cbClsExtra = std::mem::size_of::<*const Arc<Mutex<Agent>>>() as i32;
+        wndclass.cbClsExtra = std::mem::size_of::<*const Arc<Service>>() as i32;
         if RegisterClassA(&wndclass) == 0 {
             check_error().wrap_err("RegisterClassA")?;
         }
@@ -130,7 +107,7 @@ pub fn listen_pageant(agent: Arc<Mutex<Agent>>) -> Result<()> {
         if window.is_null() {
             check_error().wrap_err("CreateWindowExA")?;
         }
-        SetClassLongPtrA(window, 0, &agent as *const _ as isize);
+        SetClassLongPtrA(window, 0, &service as *const _ as isize);
 
         let mut msg: MSG = MaybeUninit::zeroed().assume_init();
         loop {
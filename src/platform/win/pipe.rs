@@ -1,132 +1,108 @@
+use std::ffi::c_void;
 use std::io;
-use std::io::{Read, Write};
+use std::mem::size_of;
+use std::os::windows::io::AsRawHandle;
 use std::ptr::null_mut;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use winapi::ctypes::c_void;
-use winapi::shared::minwindef::ULONG;
-use winapi::um::fileapi::{FlushFileBuffers, ReadFile, WriteFile};
-use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
-use winapi::um::namedpipeapi::{ConnectNamedPipe, DisconnectNamedPipe};
-use winapi::um::winbase::{CreateNamedPipeA, GetNamedPipeClientProcessId, PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT};
-use winapi::um::winnt::HANDLE;
-use wrapperrs::Result;
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use winapi::shared::minwindef::{DWORD, FALSE, ULONG};
+use winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+use winapi::um::winbase::{GetNamedPipeClientProcessId, LocalFree};
+use winapi::um::winnt::PSECURITY_DESCRIPTOR;
+use wrapperrs::{Error, Result, ResultExt};
 
 use crate::agent::{Agent, RequestInfo};
-use crate::utils::{connection_handler, ReadWrite};
-
-use super::check_error;
-use super::utils::collect_requester_info;
-
-const PIPE_NAME: *const i8 = "\\\\.\\pipe\\openssh-ssh-agent\0".as_ptr() as *const i8;
-
-struct Pipe(HANDLE);
-
-impl Read for Pipe {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        unsafe {
-            let mut read = 0;
-            if ReadFile(
-                self.0,
-                buf.as_mut_ptr() as *mut c_void,
-                buf.len() as u32,
-                &mut read,
-                null_mut(),
-            ) == 0
-            {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(read as usize)
-            }
+use crate::server::{BoxFuture, Listener};
+
+use super::utils::{collect_requester_info, current_user_sid, StrExt};
+
+pub const AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+/// Protected DACL, so that only current user and system can open the pipe. Default
+/// security would let everyone read it
+struct PipeSecurity(PSECURITY_DESCRIPTOR);
+
+// Descriptor is only read after creation
+unsafe impl Send for PipeSecurity {}
+unsafe impl Sync for PipeSecurity {}
+
+impl PipeSecurity {
+    fn current_user() -> Result<PipeSecurity> {
+        let sid = unsafe { current_user_sid() }.wrap_err("get current user")?;
+        let sddl = format!("D:P(A;;GA;;;{})(A;;GA;;;SY)", sid);
+        let mut descriptor = null_mut();
+        let converted = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_str().to_utf16_null().as_ptr(), SDDL_REVISION_1 as DWORD, &mut descriptor,
+                null_mut())
+        };
+        if converted == 0 {
+            return Err(Error::new("ConvertStringSecurityDescriptorToSecurityDescriptorW").into());
         }
+        Ok(PipeSecurity(descriptor))
     }
-}
 
-impl Write for Pipe {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    fn create(&self, name: &str) -> io::Result<NamedPipeServer> {
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: self.0,
+            bInheritHandle: FALSE,
+        };
         unsafe {
-            let mut written = 0;
-            if WriteFile(
-                self.0,
-                buf.as_ptr() as *const c_void,
-                buf.len() as u32,
-                &mut written,
-                null_mut(),
-            ) == 0
-            {
-                return Err(io::Error::last_os_error());
-            };
-            Ok(written as usize)
+            ServerOptions::new().create_with_security_attributes_raw(
+                name, &mut attributes as *mut SECURITY_ATTRIBUTES as *mut c_void)
         }
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        unsafe {
-            if FlushFileBuffers(self.0) == 0 {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(())
-            }
-        }
+impl Drop for PipeSecurity {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.0) };
     }
 }
 
-impl ReadWrite for Pipe {
-    fn read(&mut self) -> &mut dyn Read {
-        self
-    }
+/// Named pipe, a new pipe instance is created for every client
+pub struct PipeListener {
+    agent: Arc<Agent>,
+    name: &'static str,
+    channel: &'static str,
+    security: PipeSecurity,
+    next: NamedPipeServer,
+}
 
-    fn write(&mut self) -> &mut dyn Write {
-        self
+impl PipeListener {
+    /// Has to be called within tokio runtime
+    pub fn new(agent: Arc<Agent>, name: &'static str, channel: &'static str)
+               -> Result<PipeListener> {
+        let security = PipeSecurity::current_user()?;
+        let next = security.create(name).wrap_err(&format!("create named pipe {}", name))?;
+        Ok(PipeListener { agent, name, channel, security, next })
     }
 }
 
-pub fn listen_named_pipe(agent: Arc<Mutex<Agent>>) -> Result<()> {
-    unsafe {
-        loop {
-            let pipe = CreateNamedPipeA(
-                PIPE_NAME,
-                PIPE_ACCESS_DUPLEX,
-                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
-                PIPE_UNLIMITED_INSTANCES,
-                1024,
-                1024,
-                0,
-                null_mut(),
-            );
-            if pipe == INVALID_HANDLE_VALUE {
-                check_error()?;
-            }
+impl Listener for PipeListener {
+    type Stream = NamedPipeServer;
 
-            if ConnectNamedPipe(pipe, null_mut()) == 0 {
-                check_error()?;
-            }
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Self::Stream, RequestInfo)>> {
+        Box::pin(async move {
+            self.next.connect().await?;
+            let pipe = std::mem::replace(&mut self.next, self.security.create(self.name)?);
 
-            let requester = {
+            let requester = unsafe {
                 let mut process_id: ULONG = 0;
-                if GetNamedPipeClientProcessId(pipe, &mut process_id) == 0 {
+                if GetNamedPipeClientProcessId(pipe.as_raw_handle() as _, &mut process_id) == 0 {
                     None
                 } else {
                     Some(process_id)
                 }
-            }.and_then(|pid| {
-                let agent = agent.lock().unwrap();
-                collect_requester_info(agent.config(), pid).ok()
-            });
+            }.and_then(|pid| unsafe { collect_requester_info(&self.agent.config(), pid) }.ok());
 
-            let agent = agent.clone();
-            let pipe = pipe as u64;
-            std::thread::spawn(move || {
-                let pipe = pipe as HANDLE;
-
-                connection_handler(agent, &mut Pipe(pipe), RequestInfo {
-                    channel: "Pipe",
-                    requester,
-                });
-
-                DisconnectNamedPipe(pipe);
-                CloseHandle(pipe);
-            });
-        }
+            Ok((pipe, RequestInfo {
+                channel: self.channel,
+                requester,
+            }))
+        })
     }
 }
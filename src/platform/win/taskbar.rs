@@ -1,7 +1,7 @@
 use std::ffi::CString;
 use std::mem::{MaybeUninit, size_of, size_of_val};
 use std::ptr::{copy_nonoverlapping, null_mut};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use lazy_static::lazy_static;
 use openssl::hash::{hash, MessageDigest};
@@ -57,7 +57,7 @@ unsafe extern "system" fn wnd_proc(
                 let menu = LoadMenuA(GetModuleHandleA(null_mut()), MAKEINTRESOURCEA(201));
                 let menu = GetSubMenu(menu, 0);
 
-                let agent = &*(GetClassLongPtrA(window, 0) as *const Arc<Mutex<Agent>>);
+                let agent = &*(GetClassLongPtrA(window, 0) as *const Arc<Agent>);
 
                 let x = (wparam & 0xffff) as i32;
                 let y = ((wparam >> 16) & 0xffff) as i32;
@@ -72,8 +72,7 @@ unsafe extern "system" fn wnd_proc(
                 ) {
                     1 => PostQuitMessage(0),
                     2 => {
-                        let mut lock = agent.lock().unwrap();
-                        if let Err(err) = lock.config_mut().reload().wrap_err("reload config") {
+                        if let Err(err) = agent.reload_config().wrap_err("reload config") {
                             show_error(err.into());
                         }
                     }
@@ -86,14 +85,14 @@ unsafe extern "system" fn wnd_proc(
     }
 }
 
-pub fn create_taskbar_icon(agent: &Arc<Mutex<Agent>>) -> Result<()> {
+pub fn create_taskbar_icon(agent: &Arc<Agent>) -> Result<()> {
     unsafe {
         let class_name = CString::new(NAME).unwrap();
 
         let mut wndclass: WNDCLASSA = MaybeUninit::zeroed().assume_init();
         wndclass.lpfnWndProc = Some(wnd_proc);
         wndclass.lpszClassName = class_name.as_ptr();
-        wndclass.cbClsExtra = size_of::<*const Arc<Mutex<Agent>>>() as i32;
+        wndclass.cbClsExtra = size_of::<*const Arc<Agent>>() as i32;
         if RegisterClassA(&wndclass) == 0 {
             check_error().wrap_err("RegisterClassA")?;
         }
@@ -1,46 +1,86 @@
 use std::fs::{create_dir_all, remove_file};
+use std::io;
 use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
+use std::net::Shutdown;
+use std::path::PathBuf;
+use std::sync::Arc;
 
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::runtime::Handle;
 use uds_windows::{UnixListener, UnixStream};
 use winapi::um::knownfolders::FOLDERID_Profile;
 use wrapperrs::Result;
 
-use crate::agent::{Agent, RequestInfo};
-use crate::utils::{connection_handler, ReadWrite};
+use crate::agent::RequestInfo;
+use crate::agent::wire::MAX_MESSAGE_LEN;
+use crate::server::{BoxFuture, Listener};
 
 use super::utils::get_known_folder;
 
-impl ReadWrite for UnixStream {
-    fn read(&mut self) -> &mut dyn Read {
-        self
-    }
+pub fn socket_path() -> PathBuf {
+    get_known_folder(FOLDERID_Profile).join(".ssh/auth_sock")
+}
+
+/// AF_UNIX socket for WSL. Tokio doesn't support these sockets on windows, so each
+/// blocking stream is bridged to an in-memory duplex stream by two threads
+pub struct UnixSocketListener(Arc<UnixListener>);
 
-    fn write(&mut self) -> &mut dyn Write {
-        self
+impl UnixSocketListener {
+    pub fn bind() -> Result<UnixSocketListener> {
+        let socket_path = socket_path();
+
+        create_dir_all(socket_path.parent().unwrap())?;
+        #[allow(unused_must_use)]
+            {
+                remove_file(&socket_path);
+            }
+        Ok(UnixSocketListener(Arc::new(UnixListener::bind(socket_path)?)))
     }
 }
 
-pub fn listen_unix_socket(agent: Arc<Mutex<Agent>>) -> Result<()> {
-    let socket_path = get_known_folder(FOLDERID_Profile).join(".ssh/auth_sock");
+fn bridge(stream: UnixStream) -> io::Result<DuplexStream> {
+    let (service_side, bridge_side) = tokio::io::duplex(MAX_MESSAGE_LEN);
+    let (mut bridge_read, mut bridge_write) = tokio::io::split(bridge_side);
+    let handle = Handle::current();
+
+    let mut stream_read = stream.try_clone()?;
+    let read_handle = handle.clone();
+    std::thread::spawn(move || {
+        let mut buf = vec![0; 8192];
+        while let Ok(len) = stream_read.read(&mut buf) {
+            if len == 0 || read_handle.block_on(bridge_write.write_all(&buf[..len])).is_err() {
+                break;
+            }
+        }
+        let _ = read_handle.block_on(bridge_write.shutdown());
+    });
 
-    create_dir_all(socket_path.parent().unwrap())?;
-    #[allow(unused_must_use)]
-        {
-            remove_file(&socket_path);
+    let mut stream_write = stream;
+    std::thread::spawn(move || {
+        let mut buf = vec![0; 8192];
+        while let Ok(len) = handle.block_on(bridge_read.read(&mut buf)) {
+            if len == 0 || stream_write.write_all(&buf[..len]).is_err() {
+                break;
+            }
         }
-    let listener = UnixListener::bind(socket_path)?;
+        // Unblocks reading thread when service closes connection
+        let _ = stream_write.shutdown(Shutdown::Both);
+    });
+
+    Ok(service_side)
+}
 
-    for stream in listener.incoming() {
-        let mut stream = stream?;
-        let agent = agent.clone();
-        std::thread::spawn(move || {
-            connection_handler(agent, &mut stream, RequestInfo {
+impl Listener for UnixSocketListener {
+    type Stream = DuplexStream;
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Self::Stream, RequestInfo)>> {
+        let listener = self.0.clone();
+        Box::pin(async move {
+            let (stream, _) = tokio::task::spawn_blocking(move || listener.accept()).await??;
+            Ok((bridge(stream)?, RequestInfo {
                 channel: "Unix",
                 requester: None,
-            });
-        });
+            }))
+        })
     }
-
-    Ok(())
 }
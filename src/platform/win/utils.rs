@@ -12,16 +12,19 @@ use winapi::shared::guiddef::GUID;
 use winapi::shared::minwindef::{BOOL, DWORD, FALSE, LPARAM, TRUE};
 use winapi::shared::ntdef::{HANDLE, UNICODE_STRING};
 use winapi::shared::ntstatus::STATUS_SUCCESS;
+use winapi::shared::sddl::ConvertSidToStringSidW;
 use winapi::shared::windef::HWND;
 use winapi::um::combaseapi::CoTaskMemFree;
 use winapi::um::errhandlingapi::GetLastError;
 use winapi::um::handleapi::CloseHandle;
 use winapi::um::memoryapi::ReadProcessMemory;
-use winapi::um::processthreadsapi::OpenProcess;
+use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcess, OpenProcessToken};
 use winapi::um::psapi::GetModuleFileNameExW;
+use winapi::um::securitybaseapi::GetTokenInformation;
 use winapi::um::shlobj::SHGetKnownFolderPath;
 use winapi::um::winbase::{FORMAT_MESSAGE_ALLOCATE_BUFFER, FORMAT_MESSAGE_FROM_SYSTEM, FormatMessageA, LocalFree};
-use winapi::um::winnt::{LANG_USER_DEFAULT, LPSTR, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_VM_READ, PWSTR};
+use winapi::um::winnt::{LANG_USER_DEFAULT, LPSTR, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_VM_READ, PWSTR,
+                        TOKEN_QUERY, TOKEN_USER, TokenUser};
 use winapi::um::winuser::{EnumWindows, GetWindowTextLengthW, GetWindowTextW, GetWindowThreadProcessId, IsWindowVisible};
 use winapi::um::winver::{GetFileVersionInfoSizeW, GetFileVersionInfoW, VerQueryValueW};
 use wrapperrs::Error;
@@ -92,6 +95,32 @@ pub fn get_known_folder(folder_id: GUID) -> PathBuf {
     }
 }
 
+/// SID of the user running this process, e.g. `S-1-5-21-...`
+pub unsafe fn current_user_sid() -> wrapperrs::Result<String> {
+    let mut token = null_mut();
+    if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+        return Err(Error::new("OpenProcessToken").into());
+    }
+    let _close_token = close_handle(token);
+
+    let mut len: DWORD = 0;
+    GetTokenInformation(token, TokenUser, null_mut(), 0, &mut len);
+    // u64 elements keep TOKEN_USER aligned
+    let mut buf = vec![0u64; (len as usize).div_ceil(8)];
+    if GetTokenInformation(token, TokenUser, buf.as_mut_ptr() as _, len, &mut len) == 0 {
+        return Err(Error::new("GetTokenInformation").into());
+    }
+    let user = &*(buf.as_ptr() as *const TOKEN_USER);
+
+    let mut sid: PWSTR = null_mut();
+    if ConvertSidToStringSidW(user.User.Sid, &mut sid) == 0 {
+        return Err(Error::new("ConvertSidToStringSidW").into());
+    }
+    let _free_sid = Finally::new(move || { LocalFree(sid as *mut c_void); });
+    let len = (0..).take_while(|&i| *sid.offset(i) != 0).count();
+    Ok(String::from_utf16_lossy(std::slice::from_raw_parts(sid, len)))
+}
+
 pub unsafe fn get_executable_from_pid(pid: u32) -> wrapperrs::Result<PathBuf> {
     let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
     if process == null_mut() {
@@ -272,6 +301,7 @@ pub unsafe fn get_process_command_line(pid: u32) -> Result<String, ()> {
 
 pub unsafe fn collect_requester_info(_config: &Config, mut pid: u32)
     -> wrapperrs::Result<RequesterInfo> {
+    let requester_pid = pid;
     let mut process_stack = Vec::new();
     while pid != 0 {
         let window = find_primary_window(pid);
@@ -301,5 +331,6 @@ pub unsafe fn collect_requester_info(_config: &Config, mut pid: u32)
     Ok(RequesterInfo {
         description_short: short,
         description_long: long,
+        pid: Some(requester_pid),
     })
 }
@@ -0,0 +1,37 @@
+use std::fs::read_to_string;
+use std::io::{Read, stdin};
+
+use clap::ArgMatches;
+use wrapperrs::{Error, Result, ResultExt};
+
+use ssh_keys::agent::Agent;
+use ssh_keys::client::identities;
+use ssh_keys::key::{PublicKey, PublicKeyFormat};
+
+pub fn pubkey(agent: &Agent, opts: &ArgMatches) -> Result<()> {
+    let format = opts.value_of("format").unwrap().parse::<PublicKeyFormat>()?;
+
+    let exported = if let Some(path) = opts.value_of("import") {
+        let data = if path == "-" {
+            let mut data = String::new();
+            stdin().read_to_string(&mut data)?;
+            data
+        } else {
+            read_to_string(path).wrap_err("read public key file")?
+        };
+
+        let (key, comment) = PublicKey::import(&data).wrap_err("import public key")?;
+        key.export(format, comment.as_deref())?
+    } else {
+        let key_name = opts.value_of("name").unwrap();
+        let key = identities(agent)?
+            .into_iter()
+            .find(|identity| identity.comment == key_name)
+            .ok_or(Error::new("key not found"))?;
+
+        key.key.export(format, Some(&key.comment))?
+    };
+
+    print!("{}", exported);
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+use wrapperrs::Result;
+
+use ssh_keys::audit::{AuditLog, AuditRecord, format_timestamp, parse_time};
+use ssh_keys::platform::config_dir;
+
+#[derive(Default)]
+struct Filter<'a> {
+    /// Key name or fingerprint
+    key: Option<&'a str>,
+    /// Case insensitive substring of requester description
+    requester: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl Filter<'_> {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        if let Some(key) = self.key {
+            if record.name.as_deref() != Some(key) && record.fingerprint.as_deref() != Some(key) {
+                return false;
+            }
+        }
+        if let Some(requester) = &self.requester {
+            if !record.requester.as_ref()
+                .is_some_and(|description| description.to_lowercase().contains(requester)) {
+                return false;
+            }
+        }
+        self.since.is_none_or(|since| record.timestamp >= since)
+            && self.until.is_none_or(|until| record.timestamp <= until)
+    }
+}
+
+fn print_record(record: &AuditRecord) -> Result<()> {
+    let mut line = format!("{}  {:<6}  {:<7}  {}", format_timestamp(record.timestamp),
+                           serde_json::to_value(record.op)?.as_str().unwrap_or_default(),
+                           serde_json::to_value(record.decision)?.as_str().unwrap_or_default(),
+                           record.channel);
+    if let Some(name) = &record.name {
+        line += &format!("  {}", name);
+    }
+    if let Some(fingerprint) = &record.fingerprint {
+        line += &format!("  {}", fingerprint);
+    }
+    if let Some(destination) = &record.destination {
+        line += &format!("  user {}", destination);
+    }
+    if let Some(rule) = &record.rule {
+        line += &format!("  by {}", rule);
+    }
+    if let Some(pid) = record.requester_pid {
+        line += &format!("  pid {}", pid);
+    }
+    if let Some(requester) = &record.requester {
+        // Long description spans several lines, first one names the process
+        line += &format!("  {}", requester.lines().next().unwrap_or_default());
+    }
+    println!("{}", line);
+    Ok(())
+}
+
+/// Queries audit log including rotated files
+pub fn audit(opts: &ArgMatches) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let filter = Filter {
+        key: opts.value_of("key"),
+        requester: opts.value_of("requester").map(str::to_lowercase),
+        since: opts.value_of("since").map(|s| parse_time(s, now)).transpose()?,
+        until: opts.value_of("until").map(|s| parse_time(s, now)).transpose()?,
+    };
+
+    for record in AuditLog::read(&config_dir().join("audit.log"))? {
+        if !filter.matches(&record) {
+            continue;
+        }
+        if opts.is_present("json") {
+            println!("{}", serde_json::to_string(&record)?);
+        } else {
+            print_record(&record)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_keys::audit::{AuditDecision, AuditOp};
+
+    use super::*;
+
+    fn record(timestamp: u64) -> AuditRecord {
+        AuditRecord {
+            timestamp,
+            op: AuditOp::Sign,
+            fingerprint: Some("SHA256:abc".to_string()),
+            name: Some("work".to_string()),
+            channel: "agent".to_string(),
+            requester: Some("ssh (pid 42)\nssh example.com".to_string()),
+            requester_pid: Some(42),
+            destination: None,
+            decision: AuditDecision::Allowed,
+            rule: None,
+        }
+    }
+
+    #[test]
+    fn key_filter() {
+        let record = record(100);
+        assert!(Filter { key: Some("work"), ..Filter::default() }.matches(&record));
+        assert!(Filter { key: Some("SHA256:abc"), ..Filter::default() }.matches(&record));
+        assert!(!Filter { key: Some("home"), ..Filter::default() }.matches(&record));
+    }
+
+    #[test]
+    fn requester_filter() {
+        let mut record = record(100);
+        let filter = Filter { requester: Some("example".to_string()), ..Filter::default() };
+        assert!(filter.matches(&record));
+        record.requester = Some("git (pid 7)".to_string());
+        assert!(!filter.matches(&record));
+        record.requester = None;
+        assert!(!filter.matches(&record));
+    }
+
+    #[test]
+    fn time_filter() {
+        let filter = Filter { since: Some(100), until: Some(200), ..Filter::default() };
+        assert!(filter.matches(&record(100)));
+        assert!(filter.matches(&record(200)));
+        assert!(!filter.matches(&record(99)));
+        assert!(!filter.matches(&record(201)));
+        assert!(Filter::default().matches(&record(0)));
+    }
+}
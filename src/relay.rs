@@ -0,0 +1,124 @@
+use std::io;
+use std::io::{BufReader, BufWriter, Read, stdin, stdout, Write};
+use std::io::ErrorKind::UnexpectedEof;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use clap::ArgMatches;
+use wrapperrs::{Error, Result, ResultExt};
+
+use ssh_keys::agent::wire::MAX_MESSAGE_LEN;
+use ssh_keys::client::AgentClient;
+
+/// Reads length-prefixed agent message, `None` if stream ends before next message
+fn read_frame<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let len = match r.read_u32::<BigEndian>() {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        return Err(Error::new(&format!("invalid message length: {}", len)).into());
+    }
+    let mut msg = vec![0; len];
+    r.read_exact(&mut msg).wrap_err("read message")?;
+    Ok(Some(msg))
+}
+
+fn write_frame<W: Write>(w: &mut W, msg: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(msg.len() as u32)?;
+    w.write_all(msg)?;
+    w.flush()
+}
+
+/// Forwards messages from `input` to agent and responses back to `output`,
+/// until `input` is closed
+fn relay_agent<R: Read, W: Write>(client: &mut AgentClient, input: &mut R, output: &mut W)
+                                  -> Result<()> {
+    while let Some(req) = read_frame(input).wrap_err("read request")? {
+        let resp = client.request_raw(&req)?;
+        write_frame(output, &resp).wrap_err("write response")?;
+    }
+    Ok(())
+}
+
+pub fn relay(opts: &ArgMatches) -> Result<()> {
+    if let Some(path) = opts.value_of("listen") {
+        return listen::listen(path, opts.values_of("command").unwrap().collect());
+    }
+
+    let mut client = match opts.value_of("address") {
+        Some(address) => AgentClient::connect_to(address)?,
+        None => AgentClient::connect().wrap_err("connect to agent")?,
+    };
+    relay_agent(&mut client, &mut BufReader::new(stdin()), &mut BufWriter::new(stdout()))
+}
+
+/// Unix socket on the far side, each connection is relayed over its own `ssh-keys relay`
+/// process, e.g. `ssh-keys.exe relay` for WSL2 or `ssh host ssh-keys relay`
+#[cfg(unix)]
+mod listen {
+    use std::fs::remove_file;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    use log::{error, info};
+    use ssh_keys::platform::bind_socket;
+    use ssh_keys::utils::Finally;
+    use wrapperrs::ErrorExt;
+
+    use super::*;
+
+    fn relay_connection(mut stream: UnixStream, command: &[&str]) -> Result<()> {
+        let mut child = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .wrap_err(&format!("start {}", command[0]))?;
+        let mut child_in = child.stdin.take().unwrap();
+        let mut child_out = BufReader::new(child.stdout.take().unwrap());
+        let _child = Finally::new(|| {
+            let _ = child.kill();
+            let _ = child.wait();
+        });
+
+        while let Some(req) = read_frame(&mut stream).wrap_err("read request")? {
+            write_frame(&mut child_in, &req).wrap_err("write request to relay")?;
+            let resp = read_frame(&mut child_out).wrap_err("read response from relay")?
+                .ok_or(Error::new("relay exited"))?;
+            write_frame(&mut stream, &resp).wrap_err("write response")?;
+        }
+        Ok(())
+    }
+
+    pub fn listen(path: &str, command: Vec<&str>) -> Result<()> {
+        let path = Path::new(path);
+        let listener = bind_socket(path)?;
+        let _socket = Finally::new(|| {
+            let _ = remove_file(path);
+        });
+        info!("relaying {} over {}", path.display(), command.join(" "));
+
+        for stream in listener.incoming() {
+            let stream = stream.wrap_err("accept")?;
+            let command: Vec<String> = command.iter().map(|arg| arg.to_string()).collect();
+            std::thread::spawn(move || {
+                let command: Vec<&str> = command.iter().map(String::as_str).collect();
+                if let Err(err) = relay_connection(stream, &command) {
+                    error!("{}", err.wrap("relay connection"));
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod listen {
+    use super::*;
+
+    pub fn listen(_path: &str, _command: Vec<&str>) -> Result<()> {
+        Err(Error::new("--listen is only supported on unix").into())
+    }
+}
@@ -0,0 +1,573 @@
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind::{BrokenPipe, ConnectionReset, UnexpectedEof};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+                BufReader};
+use tokio::runtime::Runtime;
+use tokio::sync::{Notify, watch};
+use wrapperrs::{Error, ErrorExt, Result, ResultExt};
+
+use crate::agent::{Agent, RequestInfo};
+use crate::agent::wire::MAX_MESSAGE_LEN;
+
+/// Pause after failed accept, e.g. when out of file descriptors, so that it isn't retried
+/// in a busy loop
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How often usage stats are saved while serving
+const STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output=T> + Send + 'a>>;
+
+#[derive(Clone, Debug)]
+pub struct ConnectionLimits {
+    /// Connections over this limit are closed right away
+    pub max_connections: usize,
+    /// How long connection can wait for next request
+    pub idle_timeout: Option<Duration>,
+    /// How long reading of a started request can take
+    pub read_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: 64,
+            idle_timeout: Some(Duration::from_secs(5 * 60)),
+            read_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionStats {
+    pub active: usize,
+    pub total: u64,
+    pub rejected: u64,
+}
+
+/// Source of client connections, e.g. unix socket or named pipe
+pub trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Self::Stream, RequestInfo)>>;
+}
+
+#[cfg(unix)]
+pub struct UnixSocketListener(tokio::net::UnixListener);
+
+#[cfg(unix)]
+impl UnixSocketListener {
+    /// Has to be called within tokio runtime
+    pub fn bind<P: AsRef<std::path::Path>>(path: P) -> Result<UnixSocketListener> {
+        let path = path.as_ref();
+        Ok(UnixSocketListener(tokio::net::UnixListener::bind(path)
+            .wrap_err(&format!("bind {}", path.display()))?))
+    }
+
+    /// Takes over listener bound elsewhere, has to be called within tokio runtime
+    pub fn from_std(listener: std::os::unix::net::UnixListener) -> Result<UnixSocketListener> {
+        listener.set_nonblocking(true)?;
+        Ok(UnixSocketListener(tokio::net::UnixListener::from_std(listener)?))
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixSocketListener {
+    type Stream = tokio::net::UnixStream;
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Self::Stream, RequestInfo)>> {
+        Box::pin(async move {
+            let (stream, _) = self.0.accept().await?;
+            Ok((stream, RequestInfo { channel: "Unix", requester: None }))
+        })
+    }
+}
+
+async fn with_timeout<F: Future>(timeout: Option<Duration>, f: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, f).await.ok(),
+        None => Some(f.await),
+    }
+}
+
+/// Counts connection as active while alive
+struct ConnectionGuard<'a>(&'a Service);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        let active = {
+            let mut stats = self.0.stats.lock().unwrap();
+            stats.active -= 1;
+            stats.active
+        };
+        debug!("connection closed active={}", active);
+        self.0.idle.notify_waiters();
+    }
+}
+
+/// Processes requests of all transports: applies connection limits and timeouts, runs
+/// requests off the async threads and stops serving on shutdown
+pub struct Service {
+    agent: Arc<Agent>,
+    limits: ConnectionLimits,
+    stats: Mutex<ConnectionStats>,
+    /// Notified when a connection closes
+    idle: Notify,
+    shutdown: watch::Sender<bool>,
+    /// Kept, so that shutdown can be signaled while no one listens
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl Service {
+    pub fn new(agent: Arc<Agent>, limits: ConnectionLimits) -> Arc<Service> {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        Arc::new(Service {
+            agent,
+            limits,
+            stats: Mutex::new(ConnectionStats::default()),
+            idle: Notify::new(),
+            shutdown,
+            shutdown_rx,
+        })
+    }
+
+    pub fn agent(&self) -> &Arc<Agent> {
+        &self.agent
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Runtime to serve on. Requests are handled on its blocking threads, which are reused
+    /// and capped by `max_connections`, as each connection has at most one request running
+    pub fn runtime(&self) -> Result<Runtime> {
+        Ok(tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .max_blocking_threads(self.limits.max_connections.max(1))
+            .build()
+            .wrap_err("create runtime")?)
+    }
+
+    /// Stops accepting connections and closes connections once their current request is done
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.shutdown_rx.borrow()
+    }
+
+    /// Changes once shutdown is requested
+    pub fn shutdown_receiver(&self) -> watch::Receiver<bool> {
+        self.shutdown_rx.clone()
+    }
+
+    /// Waits until all connections are closed
+    pub async fn wait_idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if self.stats().active == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Waits until no connection was open for at least `period`
+    pub async fn wait_inactive(&self, period: Duration) {
+        loop {
+            self.wait_idle().await;
+            let total = self.stats().total;
+            tokio::time::sleep(period).await;
+            let stats = self.stats();
+            if stats.active == 0 && stats.total == total {
+                return;
+            }
+        }
+    }
+
+    /// Saves usage stats periodically until shutdown is requested, then once more
+    pub async fn flush_stats(&self) {
+        let mut shutdown = self.shutdown_rx.clone();
+        while !self.is_shutdown() {
+            tokio::select! {
+                _ = tokio::time::sleep(STATS_FLUSH_INTERVAL) => {}
+                _ = shutdown.changed() => break,
+            }
+            let agent = self.agent.clone();
+            let _ = tokio::task::spawn_blocking(move || agent.flush_stats()).await;
+        }
+        let agent = self.agent.clone();
+        let _ = tokio::task::spawn_blocking(move || agent.flush_stats()).await;
+    }
+
+    fn open_connection(&self, info: &RequestInfo) -> Option<ConnectionGuard<'_>> {
+        let mut stats = self.stats.lock().unwrap();
+        if stats.active >= self.limits.max_connections {
+            stats.rejected += 1;
+            warn!("too many connections, closing channel={} active={}", info.channel,
+                  stats.active);
+            return None;
+        }
+        stats.active += 1;
+        stats.total += 1;
+        debug!("connection opened channel={} active={}", info.channel, stats.active);
+        Some(ConnectionGuard(self))
+    }
+
+    /// Accepts connections until shutdown, serving each in its own task
+    /// Next client connection, `None` once shutdown is requested. Accept errors are logged
+    /// and retried, so that a transient error doesn't stop the listener
+    pub async fn accept<L: Listener>(&self, listener: &mut L)
+                                     -> Option<(L::Stream, RequestInfo)> {
+        let mut shutdown = self.shutdown_rx.clone();
+        while !self.is_shutdown() {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.changed() => break,
+            };
+            match accepted {
+                Ok(accepted) => return Some(accepted),
+                Err(err) => {
+                    error!("{}", err.wrap("accept"));
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => {}
+                        _ = shutdown.changed() => break,
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    pub async fn run<L: Listener>(self: Arc<Self>, mut listener: L) -> Result<()> {
+        while let Some((stream, info)) = self.accept(&mut listener).await {
+            let service = self.clone();
+            tokio::spawn(async move { service.serve_connection(stream, info).await });
+        }
+        Ok(())
+    }
+
+    pub async fn serve_connection<S>(&self, stream: S, info: RequestInfo)
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let _connection = match self.open_connection(&info) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let info = Arc::new(info);
+        if let Err(err) = self.connection_loop(&mut BufReader::new(stream), &info).await {
+            error!("{}", err.wrap(&format!("connection error channel={}", info.channel)));
+        }
+    }
+
+    async fn connection_loop<S>(&self, stream: &mut BufReader<S>, info: &Arc<RequestInfo>)
+                                -> Result<()>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut shutdown = self.shutdown_rx.clone();
+        while !self.is_shutdown() {
+            let len = tokio::select! {
+                len = with_timeout(self.limits.idle_timeout, stream.read_u32()) => match len {
+                    None => {
+                        debug!("idle timeout channel={}", info.channel);
+                        return Ok(());
+                    }
+                    Some(Ok(0)) => return Ok(()),
+                    Some(Ok(len)) => len,
+                    Some(Err(err)) if [UnexpectedEof, BrokenPipe, ConnectionReset]
+                        .contains(&err.kind()) => return Ok(()),
+                    Some(Err(err)) => return Err(err.into()),
+                },
+                _ = shutdown.changed() => return Ok(()),
+            };
+
+            if len as usize > MAX_MESSAGE_LEN {
+                return Err(Error::new(&format!("message too long: {}", len)).into());
+            }
+            let mut msg = vec![0; len as usize];
+            with_timeout(self.limits.read_timeout, stream.read_exact(&mut msg)).await
+                .ok_or(Error::new("read timeout"))?
+                .wrap_err("read request")?;
+
+            let resp = match self.process(msg, info.clone(), stream).await? {
+                Some(resp) => resp,
+                None => {
+                    info!("client disconnected, request cancelled channel={}", info.channel);
+                    return Ok(());
+                }
+            };
+            let written = async {
+                stream.write_u32(resp.len() as u32).await?;
+                stream.write_all(&resp).await?;
+                stream.flush().await
+            }.await;
+            match written {
+                Err(err) if [BrokenPipe, ConnectionReset].contains(&err.kind()) => {
+                    debug!("client closed before response channel={}", info.channel);
+                    return Ok(());
+                }
+                written => written?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles request on blocking thread, as signing and confirmation take time.
+    /// Returns `None` if client disconnects in the meantime, so that nobody waits for
+    /// the response
+    async fn process<S>(&self, msg: Vec<u8>, info: Arc<RequestInfo>, stream: &mut S)
+                        -> Result<Option<Vec<u8>>>
+        where S: AsyncBufRead + Unpin
+    {
+        let agent = self.agent.clone();
+        let mut request = tokio::task::spawn_blocking(move || agent.handle_request(&msg, &info));
+        tokio::select! {
+            resp = &mut request => return Ok(Some(resp?)),
+            // Client sent next request already, which is read after this one completes
+            data = stream.fill_buf() => if data.map_or(true, |data| data.is_empty()) {
+                return Ok(None);
+            },
+        }
+        Ok(Some(request.await?))
+    }
+
+    /// Handles single framed request synchronously, for transports that aren't streams
+    pub fn handle_frame(&self, frame: &[u8], info: &RequestInfo) -> Result<Vec<u8>> {
+        let _connection = self.open_connection(info)
+            .ok_or(Error::new("too many connections"))?;
+        if frame.len() < 4 {
+            return Err(Error::new("frame is truncated").into());
+        }
+        let len = BigEndian::read_u32(frame) as usize;
+        if len > MAX_MESSAGE_LEN || len > frame.len() - 4 {
+            return Err(Error::new(&format!("invalid message length: {}", len)).into());
+        }
+
+        let resp = self.agent.handle_request(&frame[4..4 + len], info);
+        let mut resp_frame = vec![0; 4];
+        BigEndian::write_u32(&mut resp_frame, resp.len() as u32);
+        resp_frame.extend_from_slice(&resp);
+        Ok(resp_frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::agent::{AgentRequest, AgentResponse, SSH_AGENTC_SIGN_REQUEST};
+    use crate::config::Config;
+    use crate::key::{KeyPair, PrivateKey};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn service(limits: ConnectionLimits) -> Arc<Service> {
+        let confirm = |_: &KeyPair, _: &RequestInfo, _: &Config| true;
+        Service::new(Arc::new(Agent::new(Config::default(), Box::new(confirm))), limits)
+    }
+
+    /// Serves one end of duplex stream, returns the other one
+    fn connect(service: &Arc<Service>) -> (DuplexStream, tokio::task::JoinHandle<()>) {
+        let (client, server) = duplex(1024);
+        let service = service.clone();
+        let info = RequestInfo { channel: "Test", requester: None };
+        (client, tokio::spawn(async move { service.serve_connection(server, info).await }))
+    }
+
+    fn frame(msg: &[u8]) -> Vec<u8> {
+        let mut frame = (msg.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(msg);
+        frame
+    }
+
+    async fn read_response(client: &mut DuplexStream) -> AgentResponse {
+        let len = client.read_u32().await.unwrap();
+        let mut resp = vec![0; len as usize];
+        client.read_exact(&mut resp).await.unwrap();
+        AgentResponse::decode(&resp).unwrap()
+    }
+
+    /// Connection is closed by server
+    async fn assert_closed(client: &mut DuplexStream) {
+        let mut buf = Vec::new();
+        tokio::time::timeout(TIMEOUT, client.read_to_end(&mut buf)).await.unwrap().unwrap();
+        assert!(buf.is_empty());
+    }
+
+    fn list_request() -> Vec<u8> {
+        frame(&AgentRequest::RequestIdentities.encode().unwrap())
+    }
+
+    #[tokio::test]
+    async fn framing() {
+        let service = service(ConnectionLimits::default());
+        let (mut client, _) = connect(&service);
+
+        // Two requests in one write, second one split across writes
+        let mut buf = list_request();
+        buf.extend_from_slice(&list_request()[..3]);
+        client.write_all(&buf).await.unwrap();
+        assert!(matches!(read_response(&mut client).await, AgentResponse::IdentitiesAnswer(_)));
+        client.write_all(&list_request()[3..]).await.unwrap();
+        assert!(matches!(read_response(&mut client).await, AgentResponse::IdentitiesAnswer(_)));
+
+        // Malformed message gets failure, connection stays open
+        client.write_all(&frame(&[SSH_AGENTC_SIGN_REQUEST])).await.unwrap();
+        assert!(matches!(read_response(&mut client).await, AgentResponse::Failure));
+        client.write_all(&list_request()).await.unwrap();
+        assert!(matches!(read_response(&mut client).await, AgentResponse::IdentitiesAnswer(_)));
+    }
+
+    #[tokio::test]
+    async fn disconnect_cancels_request() {
+        let key_pair = KeyPair::new(PrivateKey::generate_ed25519().unwrap(), "test".to_string())
+            .unwrap();
+        let key = key_pair.public().clone();
+        let mut config = Config::default();
+        config.keys.push(key_pair);
+        // Confirmation waits until test is done, so that client disconnects mid-request
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let confirm = move |_: &KeyPair, _: &RequestInfo, _: &Config| {
+            let _ = released.lock().unwrap().recv();
+            true
+        };
+        let agent = Arc::new(Agent::new(config, Box::new(confirm)));
+        let service = Service::new(agent, ConnectionLimits::default());
+
+        let (mut client, connection) = connect(&service);
+        let sign = AgentRequest::SignRequest { key, data: vec![0; 32], flags: 0 };
+        client.write_all(&frame(&sign.encode().unwrap())).await.unwrap();
+        drop(client);
+        tokio::time::timeout(TIMEOUT, connection).await.unwrap().unwrap();
+        assert_eq!(service.stats().active, 0);
+        drop(release);
+    }
+
+    #[tokio::test]
+    async fn too_long_message() {
+        let service = service(ConnectionLimits::default());
+        let (mut client, _) = connect(&service);
+        client.write_u32(MAX_MESSAGE_LEN as u32 + 1).await.unwrap();
+        assert_closed(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let limits = ConnectionLimits {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..ConnectionLimits::default()
+        };
+        let service = service(limits);
+        let (mut client, connection) = connect(&service);
+        client.write_all(&list_request()).await.unwrap();
+        assert!(matches!(read_response(&mut client).await, AgentResponse::IdentitiesAnswer(_)));
+        assert_closed(&mut client).await;
+        connection.await.unwrap();
+        assert_eq!(service.stats().active, 0);
+    }
+
+    #[tokio::test]
+    async fn read_timeout() {
+        let limits = ConnectionLimits {
+            read_timeout: Some(Duration::from_millis(50)),
+            ..ConnectionLimits::default()
+        };
+        let service = service(limits);
+        let (mut client, _) = connect(&service);
+        client.write_all(&list_request()[..4]).await.unwrap();
+        assert_closed(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn max_connections() {
+        let limits = ConnectionLimits { max_connections: 1, ..ConnectionLimits::default() };
+        let service = service(limits);
+        let (mut first, _) = connect(&service);
+        first.write_all(&list_request()).await.unwrap();
+        read_response(&mut first).await;
+
+        let (mut second, _) = connect(&service);
+        assert_closed(&mut second).await;
+        let stats = service.stats();
+        assert_eq!((stats.active, stats.total, stats.rejected), (1, 1, 1));
+
+        // First connection is still served
+        first.write_all(&list_request()).await.unwrap();
+        assert!(matches!(read_response(&mut first).await, AgentResponse::IdentitiesAnswer(_)));
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let service = service(ConnectionLimits::default());
+        let (mut client, _) = connect(&service);
+        client.write_all(&list_request()).await.unwrap();
+        read_response(&mut client).await;
+
+        service.shutdown();
+        assert_closed(&mut client).await;
+        tokio::time::timeout(TIMEOUT, service.wait_idle()).await.unwrap();
+        assert!(service.is_shutdown());
+    }
+
+    /// Fails first accept, then yields given streams
+    struct FlakyListener(Vec<io::Result<DuplexStream>>);
+
+    impl Listener for FlakyListener {
+        type Stream = DuplexStream;
+
+        fn accept(&mut self) -> BoxFuture<'_, io::Result<(Self::Stream, RequestInfo)>> {
+            let next = self.0.pop();
+            Box::pin(async move {
+                match next {
+                    Some(stream) => Ok((stream?, RequestInfo { channel: "Test", requester: None })),
+                    None => std::future::pending().await,
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_error() {
+        let service = service(ConnectionLimits::default());
+        let (mut client, server) = duplex(1024);
+        let listener = FlakyListener(vec![Ok(server), Err(io::Error::from(ErrorKind::Other))]);
+        let run = tokio::spawn({
+            let service = service.clone();
+            async move { service.run(listener).await.unwrap() }
+        });
+
+        client.write_all(&list_request()).await.unwrap();
+        assert!(matches!(read_response(&mut client).await, AgentResponse::IdentitiesAnswer(_)));
+        service.shutdown();
+        tokio::time::timeout(TIMEOUT, run).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn frame_handling() {
+        let service = service(ConnectionLimits::default());
+        let info = RequestInfo { channel: "Test", requester: None };
+        let resp = service.handle_frame(&list_request(), &info).unwrap();
+        assert!(matches!(AgentResponse::decode(&resp[4..]).unwrap(),
+                         AgentResponse::IdentitiesAnswer(_)));
+        assert!(service.handle_frame(&list_request()[..3], &info).is_err());
+        assert!(service.handle_frame(&list_request()[..4], &info).is_err());
+        assert_eq!(service.stats().active, 0);
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fs::{File, rename};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use wrapperrs::{ErrorExt, Result, ResultExt};
+
+/// Number of most recent uses kept per key
+pub const RECENT_USES: usize = 5;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeyUse {
+    /// Seconds since unix epoch
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+    /// Remote user of signed authentication request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+}
+
+/// Times are seconds since unix epoch
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct KeyStats {
+    /// When key was generated or imported, unknown for keys added before stats were kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<u64>,
+    pub signatures: u64,
+    /// Most recent last
+    pub recent: Vec<KeyUse>,
+}
+
+/// Stats of all keys by fingerprint, kept in a state file separate from config, so that
+/// config isn't rewritten on every signature
+#[derive(Default)]
+pub struct UsageStats {
+    /// Stats without path are never saved
+    path: Option<PathBuf>,
+    keys: HashMap<String, KeyStats>,
+    /// Changed since last save
+    dirty: bool,
+}
+
+impl UsageStats {
+    /// Missing file is the same as empty one
+    pub fn load(path: &Path) -> Result<UsageStats> {
+        let keys = match File::open(path) {
+            Ok(mut file) => {
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                serde_json::from_slice(&buf).wrap_err(&format!("parse {}", path.display()))?
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.wrap(&format!("open {}", path.display())).into()),
+        };
+        Ok(UsageStats { path: Some(path.to_path_buf()), keys, dirty: false })
+    }
+
+    /// Empty stats, replacing file at `path` when saved
+    pub fn empty(path: &Path) -> UsageStats {
+        UsageStats { path: Some(path.to_path_buf()), keys: HashMap::new(), dirty: false }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // Same as config, file is never left half-written
+        let tmp_file = path.with_extension("json.tmp");
+        {
+            let mut file = File::create(&tmp_file)?;
+            file.write_all(&serde_json::to_vec(&self.keys)?)?;
+            file.sync_all()?;
+        }
+        rename(&tmp_file, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<&KeyStats> {
+        self.keys.get(fingerprint)
+    }
+
+    pub fn created(&mut self, fingerprint: &str) {
+        self.dirty = true;
+        self.keys.entry(fingerprint.to_string()).or_default().created = Some(now());
+    }
+
+    pub fn used(&mut self, fingerprint: &str, requester: Option<String>,
+                destination: Option<String>) {
+        self.dirty = true;
+        let stats = self.keys.entry(fingerprint.to_string()).or_default();
+        let timestamp = now();
+        stats.last_used = Some(timestamp);
+        stats.signatures += 1;
+        stats.recent.push(KeyUse { timestamp, requester, destination });
+        if stats.recent.len() > RECENT_USES {
+            stats.recent.remove(0);
+        }
+    }
+
+    pub fn remove(&mut self, fingerprint: &str) -> bool {
+        let removed = self.keys.remove(fingerprint).is_some();
+        self.dirty |= removed;
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("{}-stats-test-{}.json", crate::NAME, std::process::id()));
+        let mut stats = UsageStats::empty(&path);
+        assert!(!stats.is_dirty());
+        stats.created("SHA256:a");
+        stats.used("SHA256:a", Some("ssh".to_string()), Some("git".to_string()));
+        stats.used("SHA256:b", None, None);
+        assert!(stats.is_dirty());
+        stats.save().unwrap();
+        assert!(!stats.is_dirty());
+
+        let loaded = UsageStats::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let a = loaded.get("SHA256:a").unwrap();
+        assert!(a.created.is_some());
+        assert_eq!(a.signatures, 1);
+        assert_eq!(a.recent[0].requester.as_deref(), Some("ssh"));
+        assert_eq!(a.recent[0].destination.as_deref(), Some("git"));
+        let b = loaded.get("SHA256:b").unwrap();
+        assert_eq!((b.created, b.signatures), (None, 1));
+        assert!(loaded.get("SHA256:c").is_none());
+    }
+
+    #[test]
+    fn recent_uses_truncated() {
+        let mut stats = UsageStats::default();
+        for i in 0..RECENT_USES + 2 {
+            stats.used("SHA256:a", Some(i.to_string()), None);
+        }
+        let a = stats.get("SHA256:a").unwrap();
+        assert_eq!(a.signatures, RECENT_USES as u64 + 2);
+        let recent: Vec<_> = a.recent.iter().map(|use_| use_.requester.clone().unwrap()).collect();
+        let expected: Vec<_> = (2..RECENT_USES + 2).map(|i| i.to_string()).collect();
+        assert_eq!(recent, expected);
+    }
+
+    #[test]
+    fn missing_file() {
+        let stats = UsageStats::load(Path::new("/nonexistent/stats.json")).unwrap();
+        assert!(stats.get("SHA256:a").is_none());
+        assert!(!stats.is_dirty());
+    }
+}